@@ -19,6 +19,20 @@ fn build_tree(n: i32) -> MerkleTree {
     testree
 }
 
+fn build_tree_deferred(n: i32) -> MerkleTree {
+    let mut testree = MerkleTree::new();
+    testree.set_deferred_hashing(true);
+
+    let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(1);
+    let compression = 3;
+    for _i in 0..n {
+        let v = rng.gen_range(-n/compression..n/compression);
+        testree.insert(v);
+    }
+    testree.get_hash();
+    testree
+}
+
 fn contains(testree: &mut MerkleTree, n: i32) {
 
     let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(2);
@@ -28,11 +42,40 @@ fn contains(testree: &mut MerkleTree, n: i32) {
     }
 }
 
+fn proofs(testree: &mut MerkleTree, n: i32) {
+    let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(3);
+    for _i in 0..n {
+        let v = rng.gen_range(-n..n);
+        black_box(testree.get_proof(v));
+    }
+}
+
+// Keys sharing their first 248 bits, so every path runs close to the 256-level limit
+fn deep_keys() -> Vec<Prehashed> {
+    (0..=255u8)
+        .step_by(2)
+        .map(|last| {
+            let mut hash = vec![0; 32];
+            hash[31] = last;
+            Prehashed(hash)
+        })
+        .collect()
+}
+
+fn build_deep_tree() -> MerkleTree {
+    let mut testree = MerkleTree::new();
+    for key in deep_keys() {
+        testree.insert(key);
+    }
+    testree
+}
+
 #[inline]
 fn bench_insert(c: &mut Criterion) {
     let mut group = c.benchmark_group("group1");
     group.significance_level(0.3).sample_size(10);
     group.bench_function("bench_insert", |b| b.iter(|| build_tree (black_box(1000))));
+    group.bench_function("bench_insert_deferred", |b| b.iter(|| build_tree_deferred (black_box(1000))));
     group.finish();
 }
 
@@ -46,7 +89,31 @@ fn bench_contains(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, bench_insert, bench_contains);
+fn bench_proof(c: &mut Criterion) {
+    let n = 10000;
+    let mut benchtree = build_tree(n);
+    let mut group = c.benchmark_group("group3");
+    group.significance_level(0.3).sample_size(10);
+    group.bench_function("bench_proof", |b| b.iter(|| proofs(&mut benchtree, n)));
+    group.finish();
+}
+
+fn bench_deep_paths(c: &mut Criterion) {
+    let mut benchtree = build_deep_tree();
+    let mut group = c.benchmark_group("group4");
+    group.significance_level(0.3).sample_size(10);
+    group.bench_function("bench_deep_insert", |b| b.iter(build_deep_tree));
+    group.bench_function("bench_deep_proof", |b| {
+        b.iter(|| {
+            for key in deep_keys() {
+                black_box(benchtree.get_proof(key));
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_insert, bench_contains, bench_proof, bench_deep_paths);
 criterion_main!(benches);
 
 
@@ -0,0 +1,84 @@
+//! Self-describing proofs that a key is not in the tree.
+//!
+//! The path of an absent key ends either in an empty slot or in the leaf of a
+//! different key that shares the path so far. The verifier checks that this leaf
+//! really occupies the key's path prefix, otherwise any leaf could be passed off
+//! as proof of absence.
+
+use super::*;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AbsenceProof {
+    EmptySlot,
+    DifferentLeaf { leaf_hash: Digest, count: u64 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NonMembershipProof {
+    /// Siblings from the root down to the slot where the key would be.
+    pub siblings: Vec<ProofNode>,
+    pub absence: AbsenceProof,
+}
+
+impl NonMembershipProof {
+    /// Reads the terminal node of a plain proof; `None` if it ends in a leaf of another shape.
+    pub fn from_proof(mut proof: Vec<ProofNode>) -> Option<Self> {
+        let absence = match proof.pop()? {
+            ProofNode::None => AbsenceProof::EmptySlot,
+            ProofNode::Leaf(leaf_hash, count) => AbsenceProof::DifferentLeaf { leaf_hash, count },
+            _ => return None,
+        };
+        Some(NonMembershipProof { siblings: proof, absence })
+    }
+
+    pub fn to_proof(&self) -> Vec<ProofNode> {
+        let mut proof = self.siblings.clone();
+        proof.push(match self.absence {
+            AbsenceProof::EmptySlot => ProofNode::None,
+            AbsenceProof::DifferentLeaf { ref leaf_hash, count } => ProofNode::Leaf(leaf_hash.clone(), count),
+        });
+        proof
+    }
+
+    pub fn verify<T: Hashable>(&self, root: &[u8], key: T) -> bool {
+        self.verify_with(root, key, &EmptyHashes::Constant)
+    }
+
+    /// `verify` for a tree built with the given empty hashes.
+    pub fn verify_with<T: Hashable>(&self, root: &[u8], key: T, empty: &EmptyHashes) -> bool {
+        self.verify_hash(root, &key.hash(), empty)
+    }
+
+    pub(crate) fn verify_hash(&self, root: &[u8], key_hash: &HashBytes, empty: &EmptyHashes) -> bool {
+        if !follows_key_path(&self.siblings, key_hash) {
+            return false;
+        }
+        if let AbsenceProof::DifferentLeaf { ref leaf_hash, .. } = self.absence {
+            if leaf_hash.len() != key_hash.bytes().len() || leaf_hash == key_hash.bytes() {
+                return false;
+            }
+            let other = HashBytes::new(leaf_hash.clone());
+            if (0..self.siblings.len()).any(|level| other.bit(level) != key_hash.bit(level)) {
+                return false;
+            }
+        }
+        roothash_from_proof_with(&mut self.to_proof(), empty) == root
+    }
+}
+
+impl MerkleTree {
+    /// `None` if the key is present, the tree is empty or it has no plain proofs, see `get_proof`.
+    pub fn prove_absence<T: Hashable + Debug>(&self, key: T) -> Option<NonMembershipProof> {
+        if !self.has_proofs(NodeCommitment::Hash) {
+            return None;
+        }
+        let hash = self.hash_key(&key);
+        let root = self.root_node()?;
+        let proof = root.borrow().get_proof_hash(&hash, &self.empty_hashes);
+        let absence = NonMembershipProof::from_proof(proof)?;
+        match absence.absence {
+            AbsenceProof::DifferentLeaf { ref leaf_hash, .. } if leaf_hash == hash.bytes() => None,
+            _ => Some(absence),
+        }
+    }
+}
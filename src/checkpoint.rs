@@ -0,0 +1,166 @@
+//! Ed25519-signed checkpoints of a tree root.
+//!
+//! The signed message is the canonical text body
+//! `<tree_id>\n<sequence>\n<size>\n<root hex>\n`, so the text and binary encodings
+//! carry the same signature.
+
+use super::*;
+use codec::{to_array, ByteReader};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey, SIGNATURE_LENGTH};
+
+const SIGNATURE_PREFIX: &str = "sig ";
+const TRUNCATED: CheckpointError = CheckpointError::Malformed("truncated checkpoint");
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CheckpointError {
+    EmptyTree,
+    Malformed(&'static str),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    pub tree_id: String,
+    pub sequence: u64,
+    pub root_hash: Vec<u8>,
+    /// Number of distinct keys in the tree.
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignedCheckpoint {
+    pub checkpoint: Checkpoint,
+    pub signature: [u8; SIGNATURE_LENGTH],
+}
+
+impl Checkpoint {
+    /// The tree id has to fit on one line of the text body and in the binary length prefix.
+    pub fn from_tree(tree_id: &str, sequence: u64, tree: &MerkleTree) -> Result<Self, CheckpointError> {
+        if tree_id.is_empty() || tree_id.contains(['\n', '\r']) || tree_id.len() > u16::MAX as usize {
+            return Err(CheckpointError::Malformed("tree id"));
+        }
+        let root_hash = tree.get_hash().ok_or(CheckpointError::EmptyTree)?;
+        Ok(Checkpoint {
+            tree_id: tree_id.to_string(),
+            sequence,
+            root_hash,
+            size: tree.len() as u64,
+        })
+    }
+
+    pub fn body(&self) -> String {
+        format!("{}\n{}\n{}\n{}\n", self.tree_id, self.sequence, self.size, to_hex(&self.root_hash))
+    }
+
+    pub fn sign(self, key: &SigningKey) -> SignedCheckpoint {
+        let signature = key.sign(self.body().as_bytes()).to_bytes();
+        SignedCheckpoint { checkpoint: self, signature }
+    }
+
+    fn parse_body(lines: &[&str]) -> Result<Self, CheckpointError> {
+        if lines.len() != 4 {
+            return Err(CheckpointError::Malformed("expected four body lines"));
+        }
+        if lines[0].is_empty() {
+            return Err(CheckpointError::Malformed("empty tree id"));
+        }
+        Ok(Checkpoint {
+            tree_id: lines[0].to_string(),
+            sequence: lines[1].parse().map_err(|_| CheckpointError::Malformed("sequence"))?,
+            size: lines[2].parse().map_err(|_| CheckpointError::Malformed("size"))?,
+            root_hash: from_hex(lines[3]).ok_or(CheckpointError::Malformed("root hash"))?,
+        })
+    }
+}
+
+impl SignedCheckpoint {
+    pub fn verify(&self, key: &VerifyingKey) -> bool {
+        let signature = Signature::from_bytes(&self.signature);
+        key.verify(self.checkpoint.body().as_bytes(), &signature).is_ok()
+    }
+
+    /// Checks the signature, then `verify_proof` of the key against the signed root.
+    pub fn verify_proof<T: Hashable>(&self, public_key: &VerifyingKey, key: T, proof: &[ProofNode]) -> Option<u64> {
+        self.verify_proof_with(public_key, key, proof, &EmptyHashes::Constant)
+    }
+
+    /// `verify_proof` for a tree built with the given empty hashes.
+    pub fn verify_proof_with<T: Hashable>(
+        &self,
+        public_key: &VerifyingKey,
+        key: T,
+        proof: &[ProofNode],
+        empty: &EmptyHashes,
+    ) -> Option<u64> {
+        if !self.verify(public_key) {
+            return None;
+        }
+        verify_proof_with(&self.checkpoint.root_hash, key, proof, empty)
+    }
+
+    pub fn to_text(&self) -> String {
+        format!("{}{}{}\n", self.checkpoint.body(), SIGNATURE_PREFIX, to_hex(&self.signature))
+    }
+
+    pub fn from_text(text: &str) -> Result<Self, CheckpointError> {
+        let lines: Vec<&str> = text.lines().collect();
+        let (signature_line, body) = lines
+            .split_last()
+            .ok_or(CheckpointError::Malformed("empty checkpoint"))?;
+        let signature = signature_line
+            .strip_prefix(SIGNATURE_PREFIX)
+            .and_then(from_hex)
+            .ok_or(CheckpointError::Malformed("signature line"))?;
+        Ok(SignedCheckpoint {
+            checkpoint: Checkpoint::parse_body(body)?,
+            signature: to_signature(&signature)?,
+        })
+    }
+
+    /// Length-prefixed fields in little endian followed by the raw signature. Fails if the
+    /// tree id or the root hash is too long for its length prefix.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, CheckpointError> {
+        let checkpoint = &self.checkpoint;
+        if checkpoint.tree_id.len() > u16::MAX as usize {
+            return Err(CheckpointError::Malformed("tree id"));
+        }
+        if checkpoint.root_hash.len() > u8::MAX as usize {
+            return Err(CheckpointError::Malformed("root hash"));
+        }
+        let mut bytes = vec![];
+        bytes.extend(&(checkpoint.tree_id.len() as u16).to_le_bytes());
+        bytes.extend(checkpoint.tree_id.as_bytes());
+        bytes.extend(&checkpoint.sequence.to_le_bytes());
+        bytes.extend(&checkpoint.size.to_le_bytes());
+        bytes.push(checkpoint.root_hash.len() as u8);
+        bytes.extend(&checkpoint.root_hash);
+        bytes.extend(&self.signature);
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CheckpointError> {
+        let mut reader = ByteReader::new(bytes);
+        let mut take = |length| reader.take(length).ok_or(TRUNCATED);
+        let id_length = u16::from_le_bytes(to_array(take(2)?)) as usize;
+        let tree_id = String::from_utf8(take(id_length)?.to_vec())
+            .map_err(|_| CheckpointError::Malformed("tree id"))?;
+        let sequence = u64::from_le_bytes(to_array(take(8)?));
+        let size = u64::from_le_bytes(to_array(take(8)?));
+        let root_length = take(1)?[0] as usize;
+        let root_hash = take(root_length)?.to_vec();
+        let signature = to_signature(take(SIGNATURE_LENGTH)?)?;
+        if !reader.is_done() {
+            return Err(CheckpointError::Malformed("trailing bytes"));
+        }
+        Ok(SignedCheckpoint {
+            checkpoint: Checkpoint { tree_id, sequence, root_hash, size },
+            signature,
+        })
+    }
+}
+
+fn to_signature(bytes: &[u8]) -> Result<[u8; SIGNATURE_LENGTH], CheckpointError> {
+    if bytes.len() != SIGNATURE_LENGTH {
+        return Err(CheckpointError::Malformed("signature length"));
+    }
+    Ok(to_array(bytes))
+}
@@ -0,0 +1,198 @@
+//! Binary encodings of trees and proofs, all integers little endian.
+//!
+//! A saved tree is `MRKL`, a format byte, one byte per setting (leaf commitment, keyed,
+//! empty hashes, node commitment), the number of leaves as u64, then `[hash length: u8][key hash][count: u64]`
+//! per leaf. Secrets are never written, so keyed and hash-chain trees are loaded with
+//! `from_bytes_with`. A proof is the number of nodes as u32, then
+//! `[tag: u8][hash length: u8][hash]` per node, leaves followed by their count as u64.
+
+use super::*;
+
+const TREE_MAGIC: &[u8] = b"MRKL";
+const TREE_FORMAT: u8 = 1;
+const SETTINGS_LENGTH: usize = 4;
+// Largest value of each settings byte
+const SETTING_LIMITS: [u8; SETTINGS_LENGTH] = [1, 1, 1, 2];
+
+const TAG_LEFT: u8 = 0;
+const TAG_RIGHT: u8 = 1;
+const TAG_LEAF: u8 = 2;
+const TAG_NONE: u8 = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodeError(pub &'static str);
+
+const TRUNCATED: DecodeError = DecodeError("truncated input");
+
+pub(crate) struct ByteReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        ByteReader { bytes, position: 0 }
+    }
+
+    pub(crate) fn take(&mut self, length: usize) -> Option<&'a [u8]> {
+        let end = self.position.checked_add(length)?;
+        let slice = self.bytes.get(self.position..end)?;
+        self.position = end;
+        Some(slice)
+    }
+
+    pub(crate) fn is_done(&self) -> bool {
+        self.position == self.bytes.len()
+    }
+}
+
+pub(crate) fn to_array<const N: usize>(bytes: &[u8]) -> [u8; N] {
+    let mut array = [0; N];
+    array.copy_from_slice(bytes);
+    array
+}
+
+fn push_hash(bytes: &mut Vec<u8>, hash: &[u8]) {
+    bytes.push(hash.len() as u8);
+    bytes.extend(hash);
+}
+
+fn take_hash(reader: &mut ByteReader) -> Result<Vec<u8>, DecodeError> {
+    let length = reader.take(1).ok_or(TRUNCATED)?[0] as usize;
+    Ok(reader.take(length).ok_or(TRUNCATED)?.to_vec())
+}
+
+fn take_u64(reader: &mut ByteReader) -> Result<u64, DecodeError> {
+    Ok(u64::from_le_bytes(to_array(reader.take(8).ok_or(TRUNCATED)?)))
+}
+
+// Settings byte of each mode that changes the root or the key hashes, 0 being the default
+fn setting_bytes(tree: &MerkleTree) -> Vec<u8> {
+    vec![
+        matches!(tree.leaf_commitment, LeafCommitment::HashChain(_)) as u8,
+        tree.key_secret.is_some() as u8,
+        matches!(tree.empty_hashes, EmptyHashes::PerLevel) as u8,
+        match tree.node_commitment {
+            NodeCommitment::Hash => 0,
+            NodeCommitment::Sum => 1,
+            NodeCommitment::Counted => 2,
+        },
+    ]
+}
+
+// Settings bytes and leaves of a saved tree
+type SavedTree = (Vec<u8>, Vec<(Digest, u64)>);
+
+fn decode_tree(bytes: &[u8]) -> Result<SavedTree, DecodeError> {
+    let mut reader = ByteReader::new(bytes);
+    if reader.take(TREE_MAGIC.len()) != Some(TREE_MAGIC) {
+        return Err(DecodeError("not a saved tree"));
+    }
+    if reader.take(1) != Some(&[TREE_FORMAT]) {
+        return Err(DecodeError("unsupported tree format"));
+    }
+    let settings = reader.take(SETTINGS_LENGTH).ok_or(TRUNCATED)?.to_vec();
+    if settings.iter().zip(SETTING_LIMITS).any(|(&setting, limit)| setting > limit) {
+        return Err(DecodeError("unknown tree setting"));
+    }
+    let length = take_u64(&mut reader)?;
+    let mut leaves = vec![];
+    for _ in 0..length {
+        let hash = take_hash(&mut reader)?;
+        leaves.push((hash, take_u64(&mut reader)?));
+    }
+    if !reader.is_done() {
+        return Err(DecodeError("trailing bytes"));
+    }
+    Ok((settings, leaves))
+}
+
+impl MerkleTree {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let leaves = self.leaves();
+        let mut bytes = TREE_MAGIC.to_vec();
+        bytes.push(TREE_FORMAT);
+        bytes.extend(setting_bytes(self));
+        bytes.extend(&(leaves.len() as u64).to_le_bytes());
+        for (hash, count) in leaves {
+            push_hash(&mut bytes, &hash);
+            bytes.extend(&count.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Loads a tree saved without secrets, see `from_bytes_with` for the others.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let (settings, leaves) = decode_tree(bytes)?;
+        if settings[0] != 0 || settings[1] != 0 {
+            return Err(DecodeError("tree needs its secret"));
+        }
+        let empty_hashes = if settings[2] != 0 { EmptyHashes::PerLevel } else { EmptyHashes::Constant };
+        let node_commitment = match settings[3] {
+            0 => NodeCommitment::Hash,
+            1 => NodeCommitment::Sum,
+            _ => NodeCommitment::Counted,
+        };
+        let settings = TreeSettings { empty_hashes, node_commitment, ..TreeSettings::default() };
+        Ok(MerkleTree::with_settings(settings).with_leaves(leaves))
+    }
+
+    /// Loads a saved tree into `template`, an empty tree set up like the saved one and
+    /// holding the same secrets.
+    pub fn from_bytes_with(bytes: &[u8], template: MerkleTree) -> Result<Self, DecodeError> {
+        let (saved, leaves) = decode_tree(bytes)?;
+        if !template.is_empty() || saved != setting_bytes(&template) {
+            return Err(DecodeError("tree settings differ from the template"));
+        }
+        if leaves.iter().any(|&(_, count)| count > template.leaf_commitment.max_count()) {
+            return Err(DecodeError("count too large for the leaf commitment"));
+        }
+        Ok(template.with_leaves(leaves))
+    }
+}
+
+pub fn encode_proof(proof: &[ProofNode]) -> Vec<u8> {
+    let mut bytes = (proof.len() as u32).to_le_bytes().to_vec();
+    for node in proof {
+        match node {
+            ProofNode::Left(hash) => {
+                bytes.push(TAG_LEFT);
+                push_hash(&mut bytes, hash);
+            }
+            ProofNode::Right(hash) => {
+                bytes.push(TAG_RIGHT);
+                push_hash(&mut bytes, hash);
+            }
+            ProofNode::Leaf(hash, count) => {
+                bytes.push(TAG_LEAF);
+                push_hash(&mut bytes, hash);
+                bytes.extend(&count.to_le_bytes());
+            }
+            ProofNode::None => bytes.push(TAG_NONE),
+        }
+    }
+    bytes
+}
+
+pub fn decode_proof(bytes: &[u8]) -> Result<Vec<ProofNode>, DecodeError> {
+    let mut reader = ByteReader::new(bytes);
+    let length = u32::from_le_bytes(to_array(reader.take(4).ok_or(TRUNCATED)?));
+    let mut proof = vec![];
+    for _ in 0..length {
+        let node = match reader.take(1).ok_or(TRUNCATED)?[0] {
+            TAG_LEFT => ProofNode::Left(take_hash(&mut reader)?),
+            TAG_RIGHT => ProofNode::Right(take_hash(&mut reader)?),
+            TAG_LEAF => {
+                let hash = take_hash(&mut reader)?;
+                ProofNode::Leaf(hash, take_u64(&mut reader)?)
+            }
+            TAG_NONE => ProofNode::None,
+            _ => return Err(DecodeError("unknown proof node")),
+        };
+        proof.push(node);
+    }
+    if !reader.is_done() {
+        return Err(DecodeError("trailing bytes"));
+    }
+    Ok(proof)
+}
@@ -45,6 +45,100 @@ impl MerkleTree {
             println!("{:?}", v[i]);
         }
     }
+
+    /// Graphviz rendering; inner nodes are labelled with their truncated hash, leaves with key hash and count.
+    pub fn to_dot(&self) -> String {
+        self.dot_with_path(None)
+    }
+
+    /// Same as `to_dot`, with the nodes and edges visited by the proof of `key` highlighted.
+    pub fn to_dot_with_proof<T: Hashable + Debug>(&self, key: T) -> String {
+        self.dot_with_path(Some(&self.hash_key(&key)))
+    }
+
+    fn dot_with_path(&self, path: Option<&HashBytes>) -> String {
+        let mut dot = String::from("digraph merkle {\n    node [shape=box, fontname=\"monospace\"];\n");
+        if let Some(root) = self.root_node() {
+            root.borrow().write_dot(&mut dot, &mut 0, path, 0);
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Nested JSON objects mirroring the tree, `null` standing for empty subtrees.
+    pub fn to_json(&self) -> String {
+        let mut json = String::new();
+        match self.root_node() {
+            Some(root) => root.borrow().write_json(&mut json),
+            None => json.push_str("null"),
+        }
+        json
+    }
+}
+
+const DOT_HASH_CHARS: usize = 8;
+const HIGHLIGHT: &str = ", color=red, penwidth=2";
+
+fn short_hex(bytes: &[u8]) -> String {
+    let mut hex = to_hex(bytes);
+    hex.truncate(DOT_HASH_CHARS);
+    hex
+}
+
+impl treenode::TreeNode {
+    // Writes the subtree and returns the id given to this node
+    fn write_dot(&self, dot: &mut String, next_id: &mut usize, path: Option<&HashBytes>, level: usize) -> usize {
+        let id = *next_id;
+        *next_id += 1;
+        let style = if path.is_some() { HIGHLIGHT } else { "" };
+        if self.is_leaf() {
+            dot.push_str(&format!(
+                "    n{} [label=\"leaf {}\\ncount {}\", shape=ellipse{}];\n",
+                id,
+                short_hex(self.key_hash_raw()),
+                self.get_version().unwrap(),
+                style
+            ));
+            return id;
+        }
+        dot.push_str(&format!("    n{} [label=\"{}\"{}];\n", id, short_hex(self.version_hash_raw()), style));
+        for (bit, child) in [&self.left, &self.right].iter().enumerate() {
+            let on_path = path.filter(|hash| hash.bit(level) as usize == bit);
+            let edge_style = if on_path.is_some() { HIGHLIGHT } else { "" };
+            let child_id = match child {
+                Some(child) => child.borrow().write_dot(dot, next_id, on_path, level + 1),
+                None => {
+                    let empty_id = *next_id;
+                    *next_id += 1;
+                    dot.push_str(&format!("    n{} [label=\"empty\", shape=point{}];\n", empty_id, edge_style));
+                    empty_id
+                }
+            };
+            dot.push_str(&format!("    n{} -> n{} [label=\"{}\"{}];\n", id, child_id, bit, edge_style));
+        }
+        id
+    }
+
+    fn write_json(&self, json: &mut String) {
+        if self.is_leaf() {
+            json.push_str(&format!(
+                "{{\"kind\":\"leaf\",\"hash\":\"{}\",\"key_hash\":\"{}\",\"count\":{}}}",
+                to_hex(self.version_hash_raw()),
+                to_hex(self.key_hash_raw()),
+                self.get_version().unwrap()
+            ));
+            return;
+        }
+        json.push_str(&format!("{{\"kind\":\"inner\",\"hash\":\"{}\"", to_hex(self.version_hash_raw())));
+        for (name, child) in [("left", &self.left), ("right", &self.right)].iter() {
+            json.push_str(&format!(",\"{}\":", name));
+            match child {
+                Some(child) => child.borrow().write_json(json),
+                None => json.push_str("null"),
+            }
+        }
+        json.push('}');
+    }
 }
 
 #[cfg(test)]
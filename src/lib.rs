@@ -2,20 +2,116 @@ use std::cell::RefCell;
 use std::rc::Rc;
 use treenode::*;
 use treenode::hash_structures::*;
+pub use treenode::{roothash_from_proof, roothash_from_proof_with, verify_proof, verify_proof_with, ProofNode, LEGACY_MAX_COUNT};
+pub use treenode::hash_structures::{hmac_sha256, EmptyHashes, LeafCommitment, NodeCommitment, Prehashed, MAX_CHAIN_COUNT};
+pub use treenode::hash_structures::{from_hex, to_hex};
 use std::fmt::Debug;
 
+/// Raw SHA256 output identifying a node or a root.
+pub type Digest = Vec<u8>;
+
+/// An update would take a count past `LeafCommitment::max_count`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CountOverflow;
+
+/// Everything a tree is built with besides its keys. The defaults give `MerkleTree::new()`,
+/// and any combination of fields is a valid tree.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TreeSettings {
+    pub leaf_commitment: LeafCommitment,
+    /// Secret of a keyed tree, see `MerkleTree::keyed`.
+    pub key_secret: Option<Vec<u8>>,
+    pub empty_hashes: EmptyHashes,
+    pub node_commitment: NodeCommitment,
+}
+
 pub struct MerkleTree {
     root: Option<Rc<RefCell<TreeNode>>>,
+    leaf_commitment: LeafCommitment,
+    /// Secret of a keyed tree, under which key hashes are `HMAC-SHA256(secret, SHA256(key))`.
+    key_secret: Option<Vec<u8>>,
+    empty_hashes: EmptyHashes,
+    node_commitment: NodeCommitment,
+    /// Inner hashes are recomputed when next read instead of after every write.
+    deferred_hashing: bool,
+}
+
+impl Default for MerkleTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Key hash, under the secret of a keyed tree
+pub(crate) fn digest_key<T: Hashable>(secret: Option<&[u8]>, key: &T) -> Digest {
+    match secret {
+        Some(secret) => hmac_sha256(secret, &key.raw_hash()),
+        None => key.raw_hash(),
+    }
 }
 
 impl MerkleTree {
     pub fn new() -> Self {
-        MerkleTree { root: None }
+        Self::with_settings(TreeSettings::default())
+    }
+
+    /// Tree combining any of the settings the other constructors set one at a time.
+    pub fn with_settings(settings: TreeSettings) -> Self {
+        let TreeSettings { leaf_commitment, key_secret, empty_hashes, node_commitment } = settings;
+        MerkleTree {
+            root: None,
+            leaf_commitment,
+            key_secret,
+            empty_hashes,
+            node_commitment,
+            deferred_hashing: false,
+        }
+    }
+
+    pub fn with_leaf_commitment(leaf_commitment: LeafCommitment) -> Self {
+        Self::with_settings(TreeSettings { leaf_commitment, ..TreeSettings::default() })
+    }
+
+    /// Standard sparse Merkle tree whose empty subtrees hash by depth, see `EmptyHashes::PerLevel`.
+    /// Its proofs are checked with `verify_proof_with` and its update proofs with `verify_update_with`.
+    pub fn with_empty_hashes(empty_hashes: EmptyHashes) -> Self {
+        Self::with_settings(TreeSettings { empty_hashes, ..TreeSettings::default() })
+    }
+
+    /// Tree whose inner nodes commit to the sum of the counts below them, see `sum_tree`.
+    pub fn with_node_commitment(node_commitment: NodeCommitment) -> Self {
+        Self::with_settings(TreeSettings { node_commitment, ..TreeSettings::default() })
     }
-    pub fn contains<T: Hashable + Debug>(&mut self, key: T) -> Option<i32>{
+
+    /// Tree whose key hashes cannot be enumerated without `secret`. Proofs are verified
+    /// by passing `Prehashed(tree.key_digest(&key))` in place of the key.
+    pub fn keyed(secret: &[u8]) -> Self {
+        Self::with_settings(TreeSettings { key_secret: Some(secret.to_vec()), ..TreeSettings::default() })
+    }
+
+    /// Settings the tree was built with; `with_settings` on them gives an empty tree set up alike.
+    pub fn settings(&self) -> TreeSettings {
+        TreeSettings {
+            leaf_commitment: self.leaf_commitment.clone(),
+            key_secret: self.key_secret.clone(),
+            empty_hashes: self.empty_hashes.clone(),
+            node_commitment: self.node_commitment.clone(),
+        }
+    }
+
+    /// Hash under which the tree stores `key`.
+    pub fn key_digest<T: Hashable>(&self, key: &T) -> Digest {
+        digest_key(self.key_secret.as_deref(), key)
+    }
+
+    pub(crate) fn hash_key<T: Hashable>(&self, key: &T) -> HashBytes {
+        HashBytes::new(self.key_digest(key))
+    }
+
+    pub fn contains<T: Hashable + Debug>(&mut self, key: T) -> Option<u64>{
         match self.root {
             Some(ref node) => {
-                node.borrow().contains(key)
+                node.borrow().contains(&self.hash_key(&key))
             },
             None => {
                 None
@@ -23,10 +119,33 @@ impl MerkleTree {
         }
     }
 
+    /// With deferred hashing, writes only mark their path dirty and each inner node is
+    /// hashed once when the root or a proof is next read. Turning it off flushes.
+    pub fn set_deferred_hashing(&mut self, deferred: bool) {
+        self.deferred_hashing = deferred;
+        self.flush();
+    }
+
+    // Recomputes the inner hashes left dirty by writes
+    fn flush(&self) {
+        if let Some(ref node) = self.root {
+            rehash(node, &self.empty_hashes, &self.node_commitment);
+        }
+    }
+
+    // Root with every inner hash up to date
+    pub(crate) fn root_node(&self) -> Option<&Rc<RefCell<TreeNode>>> {
+        self.flush();
+        self.root.as_ref()
+    }
+
+    /// Panics on an empty tree, or one with hash-chain leaves or a node commitment other
+    /// than `NodeCommitment::Hash`, which `verify_proof` cannot check.
     pub fn get_proof<T: Hashable + Debug>(&mut self, key: T) -> Vec<ProofNode>{
-        match self.root {
-            Some(ref node) => {
-                node.borrow().get_proof(key)
+        assert!(self.has_proofs(NodeCommitment::Hash), "tree has no plain proofs");
+        match self.root_node() {
+            Some(node) => {
+                node.borrow().get_proof_hash(&self.hash_key(&key), &self.empty_hashes)
             },
             None => {
                 panic!();
@@ -34,35 +153,144 @@ impl MerkleTree {
         }
     }
 
+    /// Panics if the count of the key would overflow, see `try_insert`.
     pub fn insert<T: Hashable + Debug>(&mut self, key: T) {
-        match self.root {
-            Some(ref node) => {
-                node.borrow_mut().insert(key);
-            }
-            None => {
-                *self = MerkleTree {
-                    root: Some( Rc::new(RefCell::new(TreeNode::new_leaf(key.hash())))),
-                }
-            }
+        self.try_insert(key).expect("count overflow");
+    }
+
+    /// Increments the count of the key and returns the new count.
+    pub fn try_insert<T: Hashable + Debug>(&mut self, key: T) -> Result<u64, CountOverflow> {
+        self.update_count(self.hash_key(&key), |count| count.checked_add(1))
+    }
+
+    /// Adds `n` to the count of the key with a single path update and returns the new count.
+    pub fn insert_n<T: Hashable + Debug>(&mut self, key: T, n: u64) -> Result<u64, CountOverflow> {
+        self.update_count(self.hash_key(&key), |count| count.checked_add(n))
+    }
+
+    /// Sets the count of the key, 0 removing it. Panics past `LeafCommitment::max_count`.
+    pub fn set_count<T: Hashable + Debug>(&mut self, key: T, n: u64) {
+        self.write_count(self.hash_key(&key), n);
+    }
+
+    /// Count of the key, 0 if absent.
+    pub fn get_count<T: Hashable + Debug>(&self, key: T) -> u64 {
+        self.count_of_hash(&self.hash_key(&key))
+    }
+
+    /// Count stored under a key hash, 0 if absent.
+    pub(crate) fn count_of_hash(&self, hash: &HashBytes) -> u64 {
+        self.root.as_ref().and_then(|node| node.borrow().contains(hash)).unwrap_or(0)
+    }
+
+    // Replaces the count with `update(old_count)`, 0 meaning absent, in a single walk down
+    // the path. The tree is left untouched when the update overflows.
+    pub(crate) fn update_count<F: FnOnce(u64) -> Option<u64>>(&mut self, hash: HashBytes, update: F) -> Result<u64, CountOverflow> {
+        let max_count = self.leaf_commitment.max_count();
+        let update = |old| update(old).filter(|&count| count <= max_count);
+        let count = update_leaf(&mut self.root, &hash, update, &self.leaf_commitment, &self.empty_hashes)?;
+        if !self.deferred_hashing {
+            self.flush();
         }
+        Ok(count)
     }
 
-    pub fn get_hash(&self) -> Option<Vec<u8>> {
-        match self.root {
-            Some(ref node) => Some(node.borrow().version_hash_raw().clone()),
-            None => None,
+    pub(crate) fn write_count(&mut self, hash: HashBytes, count: u64) {
+        self.update_count(hash, |_| Some(count)).expect("count overflow");
+    }
+
+    // Every proof but the threshold proof shows leaves as `H(count || key_hash)` and
+    // recomputes inner hashes under one node commitment, `Hash` for the plain proofs
+    pub(crate) fn has_proofs(&self, nodes: NodeCommitment) -> bool {
+        self.leaf_commitment == LeafCommitment::Count && self.node_commitment == nodes
+    }
+
+    // Snapshot sharing every node with `self`; writes to either side copy the touched path.
+    pub(crate) fn share(&self) -> MerkleTree {
+        MerkleTree {
+            root: self.root.clone(),
+            leaf_commitment: self.leaf_commitment.clone(),
+            key_secret: self.key_secret.clone(),
+            empty_hashes: self.empty_hashes.clone(),
+            node_commitment: self.node_commitment.clone(),
+            deferred_hashing: self.deferred_hashing,
+        }
+    }
+
+    pub(crate) fn subtree_node(&self, prefix: &[u8]) -> Option<Rc<RefCell<TreeNode>>> {
+        self.root_node().and_then(|root| descend(root, prefix))
+    }
+
+    /// Every stored key hash with its count, in bit-path order.
+    pub fn leaves(&self) -> Vec<(Digest, u64)> {
+        let mut leaves = vec![];
+        if let Some(ref node) = self.root {
+            node.borrow().collect_leaves(&mut leaves);
         }
+        leaves
+            .into_iter()
+            .map(|(hash, count)| (hash.bytes().clone(), count))
+            .collect()
+    }
+
+    /// Rebuilds a tree from key hashes and their counts.
+    pub fn from_leaves<I: IntoIterator<Item = (Digest, u64)>>(leaves: I) -> Self {
+        MerkleTree::new().with_leaves(leaves)
+    }
+
+    // Writes the leaves into this tree, hashing once at the end
+    pub(crate) fn with_leaves<I: IntoIterator<Item = (Digest, u64)>>(mut self, leaves: I) -> Self {
+        let deferred = self.deferred_hashing;
+        self.deferred_hashing = true;
+        for (hash, count) in leaves {
+            self.write_count(HashBytes::new(hash), count);
+        }
+        self.set_deferred_hashing(deferred);
+        self
+    }
+
+    /// Number of distinct keys stored in the tree.
+    pub fn len(&self) -> usize {
+        self.root_node().map_or(0, |node| node.borrow().leaf_count() as usize)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.root.is_none()
+    }
+
+    pub fn get_hash(&self) -> Option<Vec<u8>> {
+        self.root_node().map(|node| node.borrow().version_hash_raw().clone())
     }
 
 }
 
 
+pub mod absence_proof;
+pub mod checkpoint;
+pub mod codec;
+#[allow(clippy::needless_range_loop, clippy::useless_conversion, clippy::match_like_matches_macro, clippy::redundant_pattern_matching)]
 mod debug_functions;
+pub mod node_store;
+pub mod partial_tree;
+pub mod range_proof;
+pub mod rank;
+pub mod root_log;
+pub mod sampling;
+pub mod sharded;
+pub mod stats;
+pub mod subtree;
+pub mod sum_tree;
+pub mod sync;
+pub mod threshold;
+pub mod transaction;
 mod treenode;
+pub mod update_proof;
+pub mod wal;
 
 
 
 #[cfg(test)]
+#[allow(clippy::needless_range_loop, clippy::partialeq_to_none, clippy::single_component_path_imports)]
 mod unit_tests;
 
 
@@ -1,7 +1,190 @@
+use merkle::codec::{decode_proof, encode_proof};
+use merkle::*;
+use std::collections::BTreeMap;
+use std::fs;
+use std::process;
+
+const USAGE: &str = "usage:
+  merkle build <keys> [--binary] --out <tree>   build a tree from newline-delimited keys,
+                                                 or u32-length-prefixed keys with --binary
+  merkle root <tree>                             print the root in hex
+  merkle prove <tree> <key> [--out <proof>]      write the proof of a key, hex to stdout without --out
+  merkle verify --root <hex> --key <key> --proof <proof> [--per-level]
+                                                 --per-level for trees with per-level empty hashes
+  merkle stats <tree>
+  merkle diff <tree> <tree>                      list the key hashes whose counts differ";
+
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Err(message) = run(&args) {
+        eprintln!("error: {}", message);
+        process::exit(1);
+    }
+}
 
+fn run(args: &[String]) -> Result<(), String> {
+    let (command, rest) = match args.split_first() {
+        Some(split) => split,
+        None => return Err(USAGE.to_string()),
+    };
+    let (positional, options) = parse_options(rest)?;
+    match (command.as_str(), positional.as_slice()) {
+        ("build", [keys]) => {
+            let out = required(&options, "out")?;
+            let bytes = read(keys)?;
+            let mut tree = MerkleTree::new();
+            if options.contains_key("binary") {
+                for key in split_binary_keys(&bytes)? {
+                    tree.insert(key);
+                }
+            } else {
+                let text = String::from_utf8(bytes).map_err(|_| format!("{} is not valid UTF-8", keys))?;
+                for line in text.lines().filter(|line| !line.is_empty()) {
+                    tree.insert(line);
+                }
+            }
+            fs::write(out, tree.to_bytes()).map_err(|e| format!("cannot write {}: {}", out, e))?;
+            println!("{}", root_hex(&tree));
+        }
+        ("root", [tree]) => println!("{}", root_hex(&load_tree(tree)?)),
+        ("prove", [tree, key]) => {
+            let mut tree = load_tree(tree)?;
+            if tree.is_empty() {
+                return Err("cannot prove against an empty tree".to_string());
+            }
+            let settings = tree.settings();
+            if settings.leaf_commitment != LeafCommitment::Count || settings.node_commitment != NodeCommitment::Hash {
+                return Err("tree has no plain proofs".to_string());
+            }
+            let proof = encode_proof(&tree.get_proof(key.as_str()));
+            match options.get("out") {
+                Some(out) => fs::write(out, proof).map_err(|e| format!("cannot write {}: {}", out, e))?,
+                None => println!("{}", to_hex(&proof)),
+            }
+        }
+        ("verify", []) => {
+            let root = from_hex(required(&options, "root")?).ok_or("--root is not valid hex")?;
+            let key = required(&options, "key")?;
+            let proof = decode_proof(&read(required(&options, "proof")?)?)
+                .map_err(|e| format!("malformed proof: {}", e.0))?;
+            let empty = if options.contains_key("per-level") { EmptyHashes::PerLevel } else { EmptyHashes::Constant };
+            match verify_proof_with(&root, key.as_str(), &proof, &empty) {
+                Some(0) => println!("valid: key is absent"),
+                Some(count) => println!("valid: key is present with count {}", count),
+                None => return Err("proof does not match the root".to_string()),
+            }
+        }
+        ("stats", [tree]) => {
+            let tree = load_tree(tree)?;
+            let stats = tree.stats();
+            println!("root: {}", root_hex(&tree));
+            println!("keys: {}", stats.leaf_nodes);
+            println!("total count: {}", stats.total_count);
+            println!("inner nodes: {}", stats.inner_nodes);
+            println!("leaf depth: max {}, average {:.2}", stats.max_leaf_depth, stats.average_leaf_depth);
+            println!("depth histogram: {:?}", stats.depth_histogram);
+            println!("single-child chains: {} ({} nodes)", stats.single_child_chains, stats.single_child_nodes);
+            println!("estimated heap bytes: {}", stats.heap_bytes);
+        }
+        ("diff", [first, second]) => {
+            let mut counts: BTreeMap<Vec<u8>, (u64, u64)> = BTreeMap::new();
+            for (hash, count) in load_tree(first)?.leaves() {
+                counts.entry(hash).or_default().0 = count;
+            }
+            for (hash, count) in load_tree(second)?.leaves() {
+                counts.entry(hash).or_default().1 = count;
+            }
+            for (hash, (a, b)) in counts.into_iter().filter(|(_, (a, b))| a != b) {
+                println!("{} {} {}", to_hex(&hash), a, b);
+            }
+        }
+        _ => return Err(USAGE.to_string()),
+    }
+    Ok(())
+}
 
+// Splits `--name value` pairs (and the bare flags) from positional arguments
+fn parse_options(args: &[String]) -> Result<(Vec<&String>, BTreeMap<&str, &String>), String> {
+    let (mut positional, mut options) = (vec![], BTreeMap::new());
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.strip_prefix("--") {
+            Some(flag @ ("binary" | "per-level")) => {
+                options.insert(flag, arg);
+            }
+            Some(name) => {
+                let value = iter.next().ok_or(format!("--{} expects a value", name))?;
+                options.insert(name, value);
+            }
+            None => positional.push(arg),
+        }
+    }
+    Ok((positional, options))
+}
 
+fn required<'a>(options: &BTreeMap<&str, &'a String>, name: &str) -> Result<&'a String, String> {
+    options.get(name).copied().ok_or(format!("missing --{}", name))
 }
 
+fn read(path: &str) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|e| format!("cannot read {}: {}", path, e))
+}
+
+fn load_tree(path: &str) -> Result<MerkleTree, String> {
+    MerkleTree::from_bytes(&read(path)?).map_err(|e| format!("{} is not a saved tree: {}", path, e.0))
+}
 
+fn root_hex(tree: &MerkleTree) -> String {
+    tree.get_hash().map_or("empty".to_string(), |root| to_hex(&root))
+}
+
+fn split_binary_keys(bytes: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+    let (mut keys, mut position) = (vec![], 0);
+    while position < bytes.len() {
+        let header = bytes.get(position..position + 4).ok_or("truncated key length")?;
+        let length = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let key = bytes.get(position + 4..position + 4 + length).ok_or("truncated key")?;
+        keys.push(key.to_vec());
+        position += 4 + length;
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_prove_verify_round_trip() {
+        let directory = std::env::temp_dir().join(format!("merkle_cli_{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = |name: &str| directory.join(name).to_str().unwrap().to_string();
+        let run_with = |args: &[&str]| run(&args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>());
+        fs::write(path("keys"), "alpha\nbeta\nbeta\ngamma\n").unwrap();
+
+        run_with(&["build", &path("keys"), "--out", &path("tree")]).unwrap();
+        let tree = MerkleTree::from_bytes(&fs::read(path("tree")).unwrap()).unwrap();
+        let root = root_hex(&tree);
+        run_with(&["prove", &path("tree"), "beta", "--out", &path("proof")]).unwrap();
+        run_with(&["verify", "--root", &root, "--key", "beta", "--proof", &path("proof")]).unwrap();
+        assert!(run_with(&["verify", "--root", &root, "--key", "alpha", "--proof", &path("proof")]).is_err());
+
+        // Sum trees load but have no plain proofs
+        let mut sum = MerkleTree::with_node_commitment(NodeCommitment::Sum);
+        sum.insert("alpha");
+        fs::write(path("sum"), sum.to_bytes()).unwrap();
+        assert_eq!(run_with(&["prove", &path("sum"), "alpha"]), Err("tree has no plain proofs".to_string()));
+
+        // Per-level trees verify with --per-level, here an absence proof ending in an empty slot
+        let mut per_level = MerkleTree::with_empty_hashes(EmptyHashes::PerLevel);
+        for key in ["alpha", "beta", "gamma", "delta", "epsilon", "zeta", "eta", "theta"] {
+            per_level.insert(key);
+        }
+        fs::write(path("per_level"), per_level.to_bytes()).unwrap();
+        let root = root_hex(&per_level);
+        run_with(&["prove", &path("per_level"), "omega", "--out", &path("proof")]).unwrap();
+        run_with(&["verify", "--root", &root, "--key", "omega", "--proof", &path("proof"), "--per-level"]).unwrap();
+        assert!(run_with(&["verify", "--root", &root, "--key", "omega", "--proof", &path("proof")]).is_err());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
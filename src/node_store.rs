@@ -0,0 +1,170 @@
+//! Content-addressed store of tree nodes shared by every persisted version.
+//!
+//! Nodes are keyed by their hash, so persisting a new version only writes the nodes
+//! on the changed paths. `prune` marks everything reachable from the retained roots
+//! and sweeps the rest.
+
+use super::*;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StoredNode {
+    Leaf { key_hash: Digest, count: u64 },
+    Inner { left: Option<Digest>, right: Option<Digest> },
+}
+
+impl StoredNode {
+    // Bytes taken by the entry, the digest key included
+    fn size(&self, digest: &[u8]) -> usize {
+        let body = match self {
+            StoredNode::Leaf { key_hash, .. } => key_hash.len() + std::mem::size_of::<u64>(),
+            StoredNode::Inner { left, right } => [left, right]
+                .iter()
+                .map(|child| 1 + child.as_ref().map_or(0, |digest| digest.len()))
+                .sum(),
+        };
+        digest.len() + body
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PruneReport {
+    pub nodes_reclaimed: usize,
+    pub bytes_reclaimed: usize,
+}
+
+#[derive(Default)]
+pub struct NodeStore {
+    nodes: HashMap<Digest, StoredNode>,
+    /// Persisted roots, oldest first.
+    versions: Vec<Digest>,
+}
+
+impl NodeStore {
+    pub fn new() -> Self {
+        NodeStore { nodes: HashMap::new(), versions: vec![] }
+    }
+
+    /// Writes the nodes of `tree` that are not stored yet and records its root as a version.
+    pub fn persist(&mut self, tree: &MerkleTree) -> Option<Digest> {
+        let root = tree.root_node()?;
+        let digest = self.persist_node(root);
+        self.versions.push(digest.clone());
+        Some(digest)
+    }
+
+    fn persist_node(&mut self, node: &Rc<RefCell<TreeNode>>) -> Digest {
+        let node = node.borrow();
+        let digest = node.version_hash_raw().clone();
+        if self.nodes.contains_key(&digest) {
+            return digest;
+        }
+        let stored = if node.is_leaf() {
+            StoredNode::Leaf {
+                key_hash: node.key_hash_raw().clone(),
+                count: node.get_version().unwrap(),
+            }
+        } else {
+            StoredNode::Inner {
+                left: node.left.as_ref().map(|child| self.persist_node(child)),
+                right: node.right.as_ref().map(|child| self.persist_node(child)),
+            }
+        };
+        self.nodes.insert(digest.clone(), stored);
+        digest
+    }
+
+    /// Rebuilds the tree with the given root, `None` if some node was pruned.
+    pub fn load(&self, root: &[u8]) -> Option<MerkleTree> {
+        self.load_with(root, MerkleTree::new())
+    }
+
+    /// Rebuilds the tree into `template`, an empty tree set up like the persisted one,
+    /// secrets included. `None` if some node was pruned or does not hash to its stored
+    /// digest under the template.
+    pub fn load_with(&self, root: &[u8], mut template: MerkleTree) -> Option<MerkleTree> {
+        if !template.is_empty() {
+            return None;
+        }
+        template.root = Some(self.load_node(root, 0, &template)?);
+        Some(template)
+    }
+
+    fn load_node(&self, digest: &[u8], level: usize, tree: &MerkleTree) -> Option<Rc<RefCell<TreeNode>>> {
+        let node = match self.nodes.get(digest)? {
+            StoredNode::Leaf { key_hash, count } => {
+                let commitment = &tree.leaf_commitment;
+                if *count > commitment.max_count() {
+                    return None;
+                }
+                let leaf = TreeNode::new_committed_leaf(HashBytes::new(key_hash.clone()), *count, commitment);
+                if leaf.version_hash_raw() != digest {
+                    return None;
+                }
+                leaf
+            }
+            StoredNode::Inner { left, right } => {
+                let load_child = |child: &Option<Digest>| match child {
+                    Some(child) => self.load_node(child, level + 1, tree).map(Some),
+                    None => Some(None),
+                };
+                let (left, right) = (load_child(left)?, load_child(right)?);
+                let hash = hash_from_children(&left, &right, level, &tree.empty_hashes, &tree.node_commitment);
+                if hash.bytes() != digest {
+                    return None;
+                }
+                TreeNode::new_inner(hash, left, right)
+            }
+        };
+        Some(Rc::new(RefCell::new(node)))
+    }
+
+    pub fn versions(&self) -> &[Digest] {
+        &self.versions
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn size_bytes(&self) -> usize {
+        self.nodes.iter().map(|(digest, node)| node.size(digest)).sum()
+    }
+
+    /// Frees every node not reachable from `retain_roots` and forgets the other versions.
+    pub fn prune(&mut self, retain_roots: &[Digest]) -> PruneReport {
+        // Mark
+        let mut reachable: HashSet<&[u8]> = HashSet::new();
+        let mut stack: Vec<&[u8]> = retain_roots.iter().map(|root| root.as_slice()).collect();
+        while let Some(digest) = stack.pop() {
+            if !reachable.insert(digest) {
+                continue;
+            }
+            if let Some(StoredNode::Inner { left, right }) = self.nodes.get(digest) {
+                stack.extend(left.iter().chain(right.iter()).map(|child| child.as_slice()));
+            }
+        }
+        // Sweep
+        let garbage: Vec<Digest> = self
+            .nodes
+            .keys()
+            .filter(|digest| !reachable.contains(digest.as_slice()))
+            .cloned()
+            .collect();
+        let mut report = PruneReport::default();
+        for digest in garbage {
+            let node = self.nodes.remove(&digest).unwrap();
+            report.nodes_reclaimed += 1;
+            report.bytes_reclaimed += node.size(&digest);
+        }
+        self.versions.retain(|root| retain_roots.contains(root));
+        report
+    }
+
+    /// Keeps only the `n` most recently persisted versions.
+    pub fn prune_keep_last(&mut self, n: usize) -> PruneReport {
+        let start = self.versions.len().saturating_sub(n);
+        let retain = self.versions[start..].to_vec();
+        self.prune(&retain)
+    }
+}
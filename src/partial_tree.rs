@@ -0,0 +1,197 @@
+//! Stateless view of a tree rebuilt from proofs against a known root.
+//!
+//! Each proof expands the path of its key, while the siblings it passes stay opaque
+//! hashes. Keys whose whole path is expanded can be looked up and inserted, and the
+//! root is recomputed exactly as the full `MerkleTree` would after the same inserts.
+//! The proofs are those of `get_proof`, so trees with a node commitment are not supported.
+
+use super::*;
+use std::mem;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PartialTreeError {
+    /// A proof is malformed or does not hash to the root.
+    InvalidProof,
+    /// The path of the key runs into a subtree known only by its hash.
+    NotCovered,
+    CountOverflow,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum PartialNode {
+    /// Subtree known only by its hash.
+    Opaque(Digest),
+    Empty,
+    Leaf(HashBytes, u64),
+    Inner(Box<PartialNode>, Box<PartialNode>),
+}
+
+pub struct PartialTree {
+    root: PartialNode,
+    empty_hashes: EmptyHashes,
+}
+
+impl PartialTree {
+    pub fn from_proofs(root: &[u8], proofs: &[Vec<ProofNode>]) -> Result<Self, PartialTreeError> {
+        Self::from_proofs_with(root, proofs, &EmptyHashes::Constant)
+    }
+
+    /// `from_proofs` for a tree built with the given empty hashes.
+    pub fn from_proofs_with(root: &[u8], proofs: &[Vec<ProofNode>], empty: &EmptyHashes) -> Result<Self, PartialTreeError> {
+        let mut tree = PartialTree { root: PartialNode::Opaque(root.to_vec()), empty_hashes: empty.clone() };
+        for proof in proofs {
+            let path = tree.path_from_proof(proof)?;
+            if roothash_from_proof_with(&mut proof.clone(), empty) != root {
+                return Err(PartialTreeError::InvalidProof);
+            }
+            tree.root = merge(mem::replace(&mut tree.root, PartialNode::Empty), path)?;
+        }
+        Ok(tree)
+    }
+
+    // Single path of nodes described by a proof. Siblings equal to the empty hash of their
+    // depth are known to be empty.
+    fn path_from_proof(&self, proof: &[ProofNode]) -> Result<PartialNode, PartialTreeError> {
+        let (terminal, siblings) = proof.split_last().ok_or(PartialTreeError::InvalidProof)?;
+        if siblings.len() > HASH_LENGTH {
+            return Err(PartialTreeError::InvalidProof);
+        }
+        let mut node = match terminal {
+            ProofNode::Leaf(key_hash, count) => PartialNode::Leaf(HashBytes::new(key_hash.clone()), *count),
+            ProofNode::None => PartialNode::Empty,
+            _ => return Err(PartialTreeError::InvalidProof),
+        };
+        for (level, sibling) in siblings.iter().enumerate().rev() {
+            let sibling_node = |hash: &Digest| {
+                if hash.as_slice() == self.empty_hashes.at_depth(level + 1) {
+                    Box::new(PartialNode::Empty)
+                } else {
+                    Box::new(PartialNode::Opaque(hash.clone()))
+                }
+            };
+            node = match sibling {
+                ProofNode::Left(hash) => PartialNode::Inner(sibling_node(hash), Box::new(node)),
+                ProofNode::Right(hash) => PartialNode::Inner(Box::new(node), sibling_node(hash)),
+                _ => return Err(PartialTreeError::InvalidProof),
+            };
+        }
+        Ok(node)
+    }
+
+    /// Count of a covered key, `None` if it is absent.
+    pub fn contains<T: Hashable>(&self, key: T) -> Result<Option<u64>, PartialTreeError> {
+        let hash = key.hash();
+        let mut node = &self.root;
+        let mut level = 0;
+        loop {
+            match node {
+                PartialNode::Opaque(_) => return Err(PartialTreeError::NotCovered),
+                PartialNode::Empty => return Ok(None),
+                PartialNode::Leaf(key_hash, count) => return Ok(if *key_hash == hash { Some(*count) } else { None }),
+                PartialNode::Inner(left, right) => node = if hash.bit(level) == 0 { left } else { right },
+            }
+            level += 1;
+        }
+    }
+
+    /// Increments the count of a covered key and returns the new count. Nothing changes on error.
+    pub fn insert<T: Hashable>(&mut self, key: T) -> Result<u64, PartialTreeError> {
+        let hash = key.hash();
+        let mut node = &mut self.root;
+        let mut level = 0;
+        while let PartialNode::Inner(left, right) = node {
+            node = if hash.bit(level) == 0 { left } else { right };
+            level += 1;
+        }
+        match node {
+            PartialNode::Opaque(_) => Err(PartialTreeError::NotCovered),
+            PartialNode::Leaf(key_hash, count) if *key_hash == hash => {
+                *count = count.checked_add(1).ok_or(PartialTreeError::CountOverflow)?;
+                Ok(*count)
+            }
+            PartialNode::Leaf(..) => {
+                let other = mem::replace(node, PartialNode::Empty);
+                *node = split(other, hash, level);
+                Ok(1)
+            }
+            _ => {
+                *node = new_leaf(hash, level, &self.empty_hashes);
+                Ok(1)
+            }
+        }
+    }
+
+    pub fn get_hash(&self) -> Digest {
+        node_hash(&self.root, 0, &self.empty_hashes)
+    }
+}
+
+// Combines two views of the same subtree, keeping whatever either one expanded
+fn merge(first: PartialNode, second: PartialNode) -> Result<PartialNode, PartialTreeError> {
+    match (first, second) {
+        (PartialNode::Opaque(_), node) | (node, PartialNode::Opaque(_)) => Ok(node),
+        (PartialNode::Inner(left1, right1), PartialNode::Inner(left2, right2)) => Ok(PartialNode::Inner(
+            Box::new(merge(*left1, *left2)?),
+            Box::new(merge(*right1, *right2)?),
+        )),
+        (first, second) if first == second => Ok(first),
+        _ => Err(PartialTreeError::InvalidProof),
+    }
+}
+
+// Leaf for `hash` in an empty slot at `level`, chained down to depth `HASH_LENGTH` when
+// leaves sit at full depth
+fn new_leaf(hash: HashBytes, level: usize, empty: &EmptyHashes) -> PartialNode {
+    let mut node = PartialNode::Leaf(hash.clone(), 1);
+    if empty.full_depth() {
+        for chain_level in (level..HASH_LENGTH).rev() {
+            node = if hash.bit(chain_level) == 0 {
+                PartialNode::Inner(Box::new(node), Box::new(PartialNode::Empty))
+            } else {
+                PartialNode::Inner(Box::new(PartialNode::Empty), Box::new(node))
+            };
+        }
+    }
+    node
+}
+
+// Replaces the leaf `other` at `level` by the subtree holding it and a new leaf for `hash`,
+// chained down to the level where their paths part, as `TreeNode::split_leaf` does
+fn split(other: PartialNode, hash: HashBytes, level: usize) -> PartialNode {
+    let other_hash = match other {
+        PartialNode::Leaf(ref other_hash, _) => other_hash.clone(),
+        _ => unreachable!("only leaves are split"),
+    };
+    let mut split = level;
+    while split + 1 < HASH_LENGTH && other_hash.bit(split) == hash.bit(split) {
+        split += 1;
+    }
+    let bit = hash.bit(split);
+    let leaf = Box::new(PartialNode::Leaf(hash.clone(), 1));
+    let mut node = if bit == 0 {
+        PartialNode::Inner(leaf, Box::new(other))
+    } else {
+        PartialNode::Inner(Box::new(other), leaf)
+    };
+    for chain_level in (level..split).rev() {
+        node = if hash.bit(chain_level) == 0 {
+            PartialNode::Inner(Box::new(node), Box::new(PartialNode::Empty))
+        } else {
+            PartialNode::Inner(Box::new(PartialNode::Empty), Box::new(node))
+        };
+    }
+    node
+}
+
+fn node_hash(node: &PartialNode, depth: usize, empty: &EmptyHashes) -> Digest {
+    match node {
+        PartialNode::Opaque(hash) => hash.clone(),
+        PartialNode::Empty => empty.at_depth(depth).to_vec(),
+        PartialNode::Leaf(key_hash, count) => hash_from_version(*count, key_hash).bytes().clone(),
+        PartialNode::Inner(left, right) => {
+            let mut bytes = node_hash(left, depth + 1, empty);
+            bytes.extend(node_hash(right, depth + 1, empty));
+            bytes.raw_hash()
+        }
+    }
+}
@@ -0,0 +1,150 @@
+//! Proofs that a list of leaves is everything the tree holds in a key-hash interval.
+//!
+//! The proof is the tree cut down to the paths of `lo` and `hi`, as `get_proof` would
+//! follow them, with every subtree between the two paths given in full. Only subtrees
+//! lying wholly outside the interval may be replaced by their hash, so the verifier
+//! sees every leaf of the interval while recomputing the root.
+
+use super::*;
+use std::cmp::Ordering;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RangeNode {
+    /// Subtree outside the interval, known only by its hash.
+    Hash(Digest),
+    Empty,
+    Leaf(Digest, u64),
+    Inner(Box<RangeNode>, Box<RangeNode>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RangeProof {
+    pub root: RangeNode,
+}
+
+// Position of a subtree's bit path relative to the interval, tracked one level at a time
+#[derive(Clone, Copy)]
+struct Window {
+    past_lo: bool,
+    before_hi: bool,
+}
+
+impl Window {
+    const ROOT: Window = Window { past_lo: false, before_hi: false };
+
+    // Window of the child reached by `bit`, `None` if all of its keys fall outside the interval
+    fn child(self, bit: u8, level: usize, lo: &HashBytes, hi: &HashBytes) -> Option<Window> {
+        let mut window = self;
+        if !window.past_lo {
+            match bit.cmp(&lo.bit(level)) {
+                Ordering::Less => return None,
+                Ordering::Greater => window.past_lo = true,
+                Ordering::Equal => {}
+            }
+        }
+        if !window.before_hi {
+            match bit.cmp(&hi.bit(level)) {
+                Ordering::Greater => return None,
+                Ordering::Less => window.before_hi = true,
+                Ordering::Equal => {}
+            }
+        }
+        Some(window)
+    }
+}
+
+impl MerkleTree {
+    /// Proof of the leaves whose key hashes lie in `[lo, hi]`, both 32 bytes.
+    /// `None` on an empty tree, an empty interval, bounds of another length or a tree
+    /// without plain proofs, see `get_proof`.
+    pub fn prove_range(&self, lo: &[u8], hi: &[u8]) -> Option<RangeProof> {
+        if lo.len() * 8 != HASH_LENGTH || hi.len() * 8 != HASH_LENGTH || lo > hi || !self.has_proofs(NodeCommitment::Hash) {
+            return None;
+        }
+        let root = self.root_node()?;
+        let (lo, hi) = (HashBytes::new(lo.to_vec()), HashBytes::new(hi.to_vec()));
+        let root = expand(&root.borrow(), 0, Window::ROOT, &lo, &hi);
+        Some(RangeProof { root })
+    }
+}
+
+fn expand(node: &TreeNode, level: usize, window: Window, lo: &HashBytes, hi: &HashBytes) -> RangeNode {
+    if node.is_leaf() {
+        return RangeNode::Leaf(node.key_hash_raw().clone(), node.get_version().unwrap());
+    }
+    let child = |slot: &Option<Rc<RefCell<TreeNode>>>, bit: u8| match slot {
+        None => RangeNode::Empty,
+        Some(child) => match window.child(bit, level, lo, hi) {
+            Some(window) => expand(&child.borrow(), level + 1, window, lo, hi),
+            None => RangeNode::Hash(child.borrow().version_hash_raw().clone()),
+        },
+    };
+    RangeNode::Inner(Box::new(child(&node.left, 0)), Box::new(child(&node.right, 1)))
+}
+
+impl RangeProof {
+    pub fn verify(&self, root: &[u8], lo: &[u8], hi: &[u8]) -> Option<Vec<(Digest, u64)>> {
+        self.verify_with(root, lo, hi, &EmptyHashes::Constant)
+    }
+
+    /// Every key hash in `[lo, hi]` with its count, in bit-path order. None if the proof
+    /// is invalid or could be hiding a leaf of the interval.
+    pub fn verify_with(&self, root: &[u8], lo: &[u8], hi: &[u8], empty: &EmptyHashes) -> Option<Vec<(Digest, u64)>> {
+        if lo.len() * 8 != HASH_LENGTH || hi.len() * 8 != HASH_LENGTH || lo > hi {
+            return None;
+        }
+        let bounds = (HashBytes::new(lo.to_vec()), HashBytes::new(hi.to_vec()));
+        let mut leaves = vec![];
+        let hash = verify_node(&self.root, Some(Window::ROOT), &mut vec![], &bounds, empty, &mut leaves)?;
+        if hash == root {
+            Some(leaves)
+        } else {
+            None
+        }
+    }
+}
+
+// Hash of the node below the bit path `path`, pushing the leaves of the interval it shows.
+// `window` is `None` outside the interval, the only place where opaque hashes are allowed.
+fn verify_node(
+    node: &RangeNode,
+    window: Option<Window>,
+    path: &mut Vec<u8>,
+    bounds: &(HashBytes, HashBytes),
+    empty: &EmptyHashes,
+    leaves: &mut Vec<(Digest, u64)>,
+) -> Option<Digest> {
+    let (lo, hi) = bounds;
+    match node {
+        RangeNode::Hash(hash) => window.map_or(Some(hash.clone()), |_| None),
+        RangeNode::Empty => Some(empty.at_depth(path.len()).to_vec()),
+        RangeNode::Leaf(key_hash, count) => {
+            if key_hash.len() * 8 != HASH_LENGTH {
+                return None;
+            }
+            let leaf = HashBytes::new(key_hash.clone());
+            if path.iter().enumerate().any(|(level, &bit)| leaf.bit(level) != bit) {
+                return None;
+            }
+            if lo.bytes() <= key_hash && key_hash <= hi.bytes() {
+                leaves.push((key_hash.clone(), *count));
+            }
+            Some(hash_from_version(*count, &leaf).bytes().clone())
+        }
+        RangeNode::Inner(left, right) => {
+            let level = path.len();
+            if level >= HASH_LENGTH {
+                return None;
+            }
+            let mut bytes = vec![];
+            for (bit, child) in [(0, left), (1, right)].iter() {
+                let child_window = window.and_then(|window| window.child(*bit, level, lo, hi));
+                path.push(*bit);
+                let hash = verify_node(child, child_window, path, bounds, empty, leaves);
+                path.pop();
+                bytes.extend(hash?);
+            }
+            Some(bytes.raw_hash())
+        }
+    }
+}
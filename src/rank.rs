@@ -0,0 +1,179 @@
+//! Rank and select over the keys in bit-path order, which is the byte order of their hashes.
+//!
+//! Inner nodes cache the number of keys and the total count below them, so both walk a
+//! single path. Under `NodeCommitment::Counted` the root commits to these numbers, and a
+//! plain proof together with the counts below each sibling shows the position of a key.
+
+use super::*;
+
+/// Number of keys and the sum of their counts.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Counts {
+    pub keys: u64,
+    pub total: u128,
+}
+
+impl Counts {
+    fn of(node: &TreeNode) -> Self {
+        Counts { keys: node.leaf_count(), total: node.total() }
+    }
+
+    fn checked_add(self, other: Counts) -> Option<Counts> {
+        Some(Counts { keys: self.keys.checked_add(other.keys)?, total: self.total.checked_add(other.total)? })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RankProof {
+    /// Proof of the key, as returned by `get_proof`.
+    pub path: Vec<ProofNode>,
+    /// Counts below each sibling of `path`, root first.
+    pub sibling_counts: Vec<Counts>,
+}
+
+impl MerkleTree {
+    /// Keys stored strictly before `key_hash`, and the sum of their counts. `None` if
+    /// `key_hash` is not a full key hash.
+    pub fn rank(&self, key_hash: &[u8]) -> Option<Counts> {
+        if key_hash.len() * 8 != HASH_LENGTH {
+            return None;
+        }
+        let root = match self.root_node() {
+            Some(root) => root,
+            None => return Some(Counts::default()),
+        };
+        let hash = HashBytes::new(key_hash.to_vec());
+        let mut rank = Counts::default();
+        let mut node = Rc::clone(root);
+        for level in 0..HASH_LENGTH {
+            let next = {
+                let current = node.borrow();
+                if current.is_leaf() {
+                    if current.key_hash_raw().as_slice() < key_hash {
+                        rank = rank.checked_add(Counts::of(&current)).unwrap();
+                    }
+                    break;
+                }
+                let next = if hash.bit(level) == 0 {
+                    &current.left
+                } else {
+                    // Everything in the left subtree comes first
+                    if let Some(ref left) = current.left {
+                        rank = rank.checked_add(Counts::of(&left.borrow())).unwrap();
+                    }
+                    &current.right
+                };
+                match next {
+                    Some(next) => Rc::clone(next),
+                    None => break,
+                }
+            };
+            node = next;
+        }
+        Some(rank)
+    }
+
+    /// Key hash and count of the key at position `index`, counting from 0.
+    pub fn select(&self, index: u64) -> Option<(Digest, u64)> {
+        let root = self.root_node()?;
+        if index >= root.borrow().leaf_count() {
+            return None;
+        }
+        let leaf = leaf_at_weight(root, index as u128, |node| node.leaf_count() as u128);
+        let leaf = leaf.borrow();
+        Some((leaf.key_hash_raw().clone(), leaf.get_version().unwrap()))
+    }
+
+    /// Proof of the rank of `key_hash` for a tree built with `NodeCommitment::Counted`.
+    /// `None` on an empty tree, one with hash-chain leaves or one built otherwise, and
+    /// if `key_hash` is not a full key hash.
+    pub fn prove_rank(&self, key_hash: &[u8]) -> Option<RankProof> {
+        if !self.has_proofs(NodeCommitment::Counted) || key_hash.len() * 8 != HASH_LENGTH {
+            return None;
+        }
+        let root = self.root_node()?;
+        let hash = HashBytes::new(key_hash.to_vec());
+        let path = root.borrow().get_proof_hash(&hash, &self.empty_hashes);
+        let sibling_counts = path_siblings(root, &hash, &self.empty_hashes)
+            .iter()
+            .map(|sibling| sibling.as_ref().map_or(Counts::default(), |sibling| Counts::of(&sibling.borrow())))
+            .collect();
+        Some(RankProof { path, sibling_counts })
+    }
+
+    /// Proof of the key at position `index`, see `verify_select`.
+    pub fn prove_select(&self, index: u64) -> Option<RankProof> {
+        let (key_hash, _) = self.select(index)?;
+        self.prove_rank(&key_hash)
+    }
+}
+
+pub fn verify_rank(root: &[u8], key_hash: &[u8], proof: &RankProof) -> Option<(Counts, u64)> {
+    verify_rank_with(root, key_hash, proof, &EmptyHashes::Constant)
+}
+
+/// Rank of `key_hash` under `root` and its count, 0 meaning absent. None if the proof is invalid.
+pub fn verify_rank_with(root: &[u8], key_hash: &[u8], proof: &RankProof, empty: &EmptyHashes) -> Option<(Counts, u64)> {
+    if key_hash.len() * 8 != HASH_LENGTH {
+        return None;
+    }
+    let key_hash = HashBytes::new(key_hash.to_vec());
+    let (terminal, siblings) = proof.path.split_last()?;
+    if siblings.len() != proof.sibling_counts.len() || !follows_key_path(siblings, &key_hash) {
+        return None;
+    }
+    let mut rank = Counts::default();
+    let (mut hash, mut counts, count) = match terminal {
+        ProofNode::Leaf(leaf_hash, count) => {
+            if leaf_hash.len() != key_hash.bytes().len() {
+                return None;
+            }
+            let leaf = HashBytes::new(leaf_hash.clone());
+            let counts = Counts { keys: 1, total: *count as u128 };
+            if leaf != key_hash {
+                // A different leaf has to occupy the key's path so far
+                if (0..siblings.len()).any(|level| leaf.bit(level) != key_hash.bit(level)) {
+                    return None;
+                }
+                if leaf.bytes() < key_hash.bytes() {
+                    rank = counts;
+                }
+            }
+            let shown = if leaf == key_hash { *count } else { 0 };
+            (hash_from_version(*count, &leaf).bytes().clone(), counts, shown)
+        }
+        ProofNode::None => (empty.at_depth(siblings.len()).to_vec(), Counts::default(), 0),
+        _ => return None,
+    };
+    for (sibling, &sibling_counts) in siblings.iter().zip(&proof.sibling_counts).rev() {
+        hash = match sibling {
+            ProofNode::Left(left) => {
+                rank = rank.checked_add(sibling_counts)?;
+                counted_node_hash(left, (sibling_counts.keys, sibling_counts.total), &hash, (counts.keys, counts.total))
+            }
+            ProofNode::Right(right) => {
+                counted_node_hash(&hash, (counts.keys, counts.total), right, (sibling_counts.keys, sibling_counts.total))
+            }
+            _ => return None,
+        };
+        counts = counts.checked_add(sibling_counts)?;
+    }
+    if hash == root {
+        Some((rank, count))
+    } else {
+        None
+    }
+}
+
+/// Count of the key at position `index` under `root`. None if the proof is invalid or
+/// shows another key at that position.
+pub fn verify_select(root: &[u8], index: u64, key_hash: &[u8], proof: &RankProof) -> Option<u64> {
+    verify_select_with(root, index, key_hash, proof, &EmptyHashes::Constant)
+}
+
+pub fn verify_select_with(root: &[u8], index: u64, key_hash: &[u8], proof: &RankProof, empty: &EmptyHashes) -> Option<u64> {
+    match verify_rank_with(root, key_hash, proof, empty)? {
+        (rank, count) if count > 0 && rank.keys == index => Some(count),
+        _ => None,
+    }
+}
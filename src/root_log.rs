@@ -0,0 +1,262 @@
+//! Tamper-evident history of the roots a `MerkleTree` went through.
+//!
+//! Every record is committed in an append-only Merkle tree following RFC 6962
+//! (Certificate Transparency), so a log of size m can be proven to be a prefix of a
+//! log of size n, and any single record can be proven to be part of the log.
+//! `Transaction::commit_logged` records each commit; `record` logs any other write.
+
+use super::*;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operation {
+    /// Single insert, carrying the key hash.
+    Insert(Vec<u8>),
+    /// Batch of the given number of inserts.
+    Batch(usize),
+    Sync,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogEntry {
+    pub sequence: u64,
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub operation: Operation,
+    /// `None` when the tree was empty.
+    pub root: Option<Vec<u8>>,
+}
+
+impl LogEntry {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend(&self.sequence.to_le_bytes());
+        bytes.extend(&self.timestamp.to_le_bytes());
+        match self.operation {
+            Operation::Insert(ref key_hash) => {
+                bytes.push(0);
+                bytes.extend(&(key_hash.len() as u32).to_le_bytes());
+                bytes.extend(key_hash);
+            }
+            Operation::Batch(size) => {
+                bytes.push(1);
+                bytes.extend(&(size as u64).to_le_bytes());
+            }
+            Operation::Sync => bytes.push(2),
+        }
+        match self.root {
+            Some(ref root) => {
+                bytes.extend(&(root.len() as u32).to_le_bytes());
+                bytes.extend(root);
+            }
+            None => bytes.extend(&0u32.to_le_bytes()),
+        }
+        bytes
+    }
+
+    pub fn leaf_hash(&self) -> Vec<u8> {
+        let mut bytes = vec![LEAF_PREFIX];
+        bytes.extend(self.encode());
+        bytes.raw_hash()
+    }
+}
+
+#[derive(Default)]
+pub struct RootLog {
+    entries: Vec<LogEntry>,
+    leaf_hashes: Vec<Vec<u8>>,
+}
+
+impl RootLog {
+    pub fn new() -> Self {
+        RootLog { entries: vec![], leaf_hashes: vec![] }
+    }
+
+    /// Records the current root of `tree` after a write made outside a logged commit;
+    /// returns the sequence number of the record.
+    pub fn record(&mut self, tree: &MerkleTree, operation: Operation) -> u64 {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0);
+        self.record_at(tree, operation, timestamp)
+    }
+
+    pub fn record_at(&mut self, tree: &MerkleTree, operation: Operation, timestamp: u64) -> u64 {
+        let entry = LogEntry {
+            sequence: self.entries.len() as u64,
+            timestamp,
+            operation,
+            root: tree.get_hash(),
+        };
+        self.leaf_hashes.push(entry.leaf_hash());
+        self.entries.push(entry);
+        self.entries.len() as u64 - 1
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn entries(&self) -> &[LogEntry] {
+        &self.entries
+    }
+
+    /// Merkle tree hash of the first `size` records.
+    pub fn log_root(&self, size: usize) -> Vec<u8> {
+        assert!(size <= self.len(), "log has only {} records", self.len());
+        subtree_hash(&self.leaf_hashes[..size])
+    }
+
+    /// Proof that the log of `old_size` records is a prefix of the log of `new_size` records.
+    pub fn consistency_proof(&self, old_size: usize, new_size: usize) -> Vec<Vec<u8>> {
+        assert!(0 < old_size && old_size <= new_size && new_size <= self.len());
+        subproof(old_size, &self.leaf_hashes[..new_size], true)
+    }
+
+    /// Audit path of the record `index` in the log of `size` records.
+    pub fn inclusion_proof(&self, index: usize, size: usize) -> Vec<Vec<u8>> {
+        assert!(index < size && size <= self.len());
+        audit_path(index, &self.leaf_hashes[..size])
+    }
+}
+
+fn largest_power_of_two_below(n: usize) -> usize {
+    let mut k = 1;
+    while k << 1 < n {
+        k <<= 1;
+    }
+    k
+}
+
+fn node_hash(left: &[u8], right: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![NODE_PREFIX];
+    bytes.extend(left);
+    bytes.extend(right);
+    bytes.raw_hash()
+}
+
+fn subtree_hash(leaves: &[Vec<u8>]) -> Vec<u8> {
+    match leaves.len() {
+        0 => Vec::<u8>::new().raw_hash(),
+        1 => leaves[0].clone(),
+        n => {
+            let k = largest_power_of_two_below(n);
+            node_hash(&subtree_hash(&leaves[..k]), &subtree_hash(&leaves[k..]))
+        }
+    }
+}
+
+fn subproof(m: usize, leaves: &[Vec<u8>], complete: bool) -> Vec<Vec<u8>> {
+    let n = leaves.len();
+    if m == n {
+        return if complete { vec![] } else { vec![subtree_hash(leaves)] };
+    }
+    let k = largest_power_of_two_below(n);
+    if m <= k {
+        let mut proof = subproof(m, &leaves[..k], complete);
+        proof.push(subtree_hash(&leaves[k..]));
+        proof
+    } else {
+        let mut proof = subproof(m - k, &leaves[k..], false);
+        proof.push(subtree_hash(&leaves[..k]));
+        proof
+    }
+}
+
+fn audit_path(index: usize, leaves: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    let n = leaves.len();
+    if n <= 1 {
+        return vec![];
+    }
+    let k = largest_power_of_two_below(n);
+    if index < k {
+        let mut proof = audit_path(index, &leaves[..k]);
+        proof.push(subtree_hash(&leaves[k..]));
+        proof
+    } else {
+        let mut proof = audit_path(index - k, &leaves[k..]);
+        proof.push(subtree_hash(&leaves[..k]));
+        proof
+    }
+}
+
+/// Checks a consistency proof as specified in RFC 9162, section 2.1.4.2.
+pub fn verify_consistency(
+    old_size: usize,
+    new_size: usize,
+    old_root: &[u8],
+    new_root: &[u8],
+    proof: &[Vec<u8>],
+) -> bool {
+    if old_size == 0 || old_size > new_size {
+        return false;
+    }
+    if old_size == new_size {
+        return proof.is_empty() && old_root == new_root;
+    }
+    let mut path = proof.to_vec();
+    if old_size.is_power_of_two() {
+        path.insert(0, old_root.to_vec());
+    }
+    if path.is_empty() {
+        return false;
+    }
+    let (mut first, mut second) = (old_size - 1, new_size - 1);
+    while first & 1 == 1 {
+        first >>= 1;
+        second >>= 1;
+    }
+    let (mut old_hash, mut new_hash) = (path[0].clone(), path[0].clone());
+    for sibling in path.iter().skip(1) {
+        if second == 0 {
+            return false;
+        }
+        if first & 1 == 1 || first == second {
+            old_hash = node_hash(sibling, &old_hash);
+            new_hash = node_hash(sibling, &new_hash);
+            while first & 1 == 0 && first != 0 {
+                first >>= 1;
+                second >>= 1;
+            }
+        } else {
+            new_hash = node_hash(&new_hash, sibling);
+        }
+        first >>= 1;
+        second >>= 1;
+    }
+    old_hash == old_root && new_hash == new_root && second == 0
+}
+
+/// Checks an audit path as specified in RFC 9162, section 2.1.3.2.
+pub fn verify_inclusion(index: usize, size: usize, entry: &LogEntry, log_root: &[u8], proof: &[Vec<u8>]) -> bool {
+    if index >= size {
+        return false;
+    }
+    let (mut position, mut last) = (index, size - 1);
+    let mut hash = entry.leaf_hash();
+    for sibling in proof {
+        if last == 0 {
+            return false;
+        }
+        if position & 1 == 1 || position == last {
+            hash = node_hash(sibling, &hash);
+            while position & 1 == 0 && position != 0 {
+                position >>= 1;
+                last >>= 1;
+            }
+        } else {
+            hash = node_hash(&hash, sibling);
+        }
+        position >>= 1;
+        last >>= 1;
+    }
+    last == 0 && hash == log_root
+}
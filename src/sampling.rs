@@ -0,0 +1,34 @@
+//! Random sampling of stored keys for audits.
+//!
+//! Every inner node caches the sum of the counts and the number of leaves below it,
+//! so a sample walks a single path from the root, choosing each child in proportion
+//! to its weight. The sampled key comes with its proof against the current root.
+
+use super::*;
+use rand::Rng;
+
+impl MerkleTree {
+    /// Key hash, count and proof of a key picked with probability proportional to its count.
+    /// `None` on an empty tree or one without plain proofs, see `get_proof`.
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> Option<(Digest, u64, Vec<ProofNode>)> {
+        self.sample_by(rng, TreeNode::total)
+    }
+
+    /// `sample` with every stored key equally likely.
+    pub fn sample_uniform<R: Rng>(&self, rng: &mut R) -> Option<(Digest, u64, Vec<ProofNode>)> {
+        self.sample_by(rng, |node| node.leaf_count() as u128)
+    }
+
+    fn sample_by<R: Rng, W: Fn(&TreeNode) -> u128>(&self, rng: &mut R, weight: W) -> Option<(Digest, u64, Vec<ProofNode>)> {
+        if !self.has_proofs(NodeCommitment::Hash) {
+            return None;
+        }
+        let root = self.root_node()?;
+        let target = rng.gen_range(0..weight(&root.borrow()));
+        let node = leaf_at_weight(root, target, weight);
+        let leaf = node.borrow();
+        let key_hash = HashBytes::new(leaf.key_hash_raw().clone());
+        let proof = root.borrow().get_proof_hash(&key_hash, &self.empty_hashes);
+        Some((key_hash.bytes().clone(), leaf.get_version().unwrap(), proof))
+    }
+}
@@ -0,0 +1,202 @@
+//! Tree split by the leading bits of the key hash into independently locked shards.
+//!
+//! Each shard holds the key hashes and counts of its prefix, so writers to different
+//! shards never wait on each other. When the root is read, every shard changed since
+//! the last read builds its own `MerkleTree` on a worker thread and keeps only the hash
+//! of its subtree at the prefix depth. The root assembled from those equals the root of
+//! one tree holding every key.
+
+use super::*;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::thread;
+
+/// Largest supported number of prefix bits, giving 65536 shards.
+pub const MAX_PREFIX_BITS: usize = 16;
+
+// Node standing for a prefix, as in the compressed tree
+#[derive(Clone)]
+enum Top {
+    Empty,
+    Leaf(Digest, u64),
+    /// Hash, number of leaves and total count below.
+    Inner(Digest, u64, u128),
+}
+
+// Keys of one prefix. Trees are only built inside `top` and dropped before it returns, so
+// no node ever leaves the thread holding the shard's lock.
+#[derive(Default)]
+struct Shard {
+    /// Count of every key hash, none of them 0.
+    leaves: BTreeMap<Digest, u64>,
+    /// Top of the shard as of its last write, `None` until computed.
+    top: Option<Top>,
+}
+
+impl Shard {
+    fn update_count<F: FnOnce(u64) -> Option<u64>>(&mut self, hash: HashBytes, update: F, max_count: u64) -> Result<u64, CountOverflow> {
+        let old = self.count_of_hash(&hash);
+        let count = update(old).filter(|&count| count <= max_count).ok_or(CountOverflow)?;
+        if count != old {
+            if count == 0 {
+                self.leaves.remove(hash.bytes());
+            } else {
+                self.leaves.insert(hash.bytes().clone(), count);
+            }
+            self.top = None;
+        }
+        Ok(count)
+    }
+
+    fn count_of_hash(&self, hash: &HashBytes) -> u64 {
+        self.leaves.get(hash.bytes()).copied().unwrap_or(0)
+    }
+
+    // Node standing for `prefix` in a tree of the shard's keys built with `settings`
+    fn top(&mut self, prefix: &[u8], settings: &TreeSettings) -> Top {
+        if let Some(ref top) = self.top {
+            return top.clone();
+        }
+        let leaves = self.leaves.iter().map(|(hash, &count)| (hash.clone(), count));
+        let tree = MerkleTree::with_settings(settings.clone()).with_leaves(leaves);
+        let top = match tree.subtree_node(prefix) {
+            Some(node) => {
+                let node = node.borrow();
+                let hash = node.version_hash_raw().clone();
+                match node.get_version() {
+                    Some(count) => Top::Leaf(hash, count),
+                    None => Top::Inner(hash, node.leaf_count(), node.total()),
+                }
+            }
+            None => Top::Empty,
+        };
+        self.top = Some(top.clone());
+        top
+    }
+}
+
+pub struct ShardedMerkleTree {
+    prefix_bits: usize,
+    shards: Vec<Mutex<Shard>>,
+    settings: TreeSettings,
+}
+
+impl ShardedMerkleTree {
+    /// Tree of `2^prefix_bits` shards. Panics above `MAX_PREFIX_BITS`.
+    pub fn new(prefix_bits: usize) -> Self {
+        Self::with_settings(prefix_bits, TreeSettings::default())
+    }
+
+    pub fn with_empty_hashes(prefix_bits: usize, empty_hashes: EmptyHashes) -> Self {
+        Self::with_settings(prefix_bits, TreeSettings { empty_hashes, ..TreeSettings::default() })
+    }
+
+    /// Shards built with `settings`, whose root is that of a single tree built with them.
+    pub fn with_settings(prefix_bits: usize, settings: TreeSettings) -> Self {
+        assert!(prefix_bits <= MAX_PREFIX_BITS, "too many prefix bits");
+        let shards = (0..1usize << prefix_bits)
+            .map(|_| Mutex::new(Shard::default()))
+            .collect();
+        ShardedMerkleTree { prefix_bits, shards, settings }
+    }
+
+    // Prefix bits of the shard at `index`, most significant first
+    fn prefix_of(&self, index: usize) -> Vec<u8> {
+        (0..self.prefix_bits).map(|level| (index >> (self.prefix_bits - 1 - level) & 1) as u8).collect()
+    }
+
+    // Index of the shard holding the key hash, its prefix bits read most significant first
+    fn shard_of(&self, hash: &HashBytes) -> usize {
+        (0..self.prefix_bits).fold(0, |index, level| index << 1 | hash.bit(level) as usize)
+    }
+
+    fn with_shard<T: Hashable, R, F: FnOnce(&mut Shard, HashBytes) -> R>(&self, key: &T, f: F) -> R {
+        let hash = HashBytes::new(digest_key(self.settings.key_secret.as_deref(), key));
+        let mut shard = self.shards[self.shard_of(&hash)].lock().unwrap();
+        f(&mut shard, hash)
+    }
+
+    /// Panics if the count of the key would overflow, see `try_insert`.
+    pub fn insert<T: Hashable + Debug>(&self, key: T) {
+        self.try_insert(key).expect("count overflow");
+    }
+
+    /// Increments the count of the key and returns the new count.
+    pub fn try_insert<T: Hashable + Debug>(&self, key: T) -> Result<u64, CountOverflow> {
+        let max_count = self.settings.leaf_commitment.max_count();
+        self.with_shard(&key, |shard, hash| shard.update_count(hash, |count| count.checked_add(1), max_count))
+    }
+
+    /// Sets the count of the key, 0 removing it.
+    pub fn set_count<T: Hashable + Debug>(&self, key: T, n: u64) {
+        let max_count = self.settings.leaf_commitment.max_count();
+        self.with_shard(&key, |shard, hash| shard.update_count(hash, |_| Some(n), max_count)).expect("count overflow");
+    }
+
+    /// Count of the key, 0 if absent.
+    pub fn get_count<T: Hashable + Debug>(&self, key: T) -> u64 {
+        self.with_shard(&key, |shard, hash| shard.count_of_hash(&hash))
+    }
+
+    /// Number of distinct keys over all shards.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.lock().unwrap().leaves.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|shard| shard.lock().unwrap().leaves.is_empty())
+    }
+
+    /// Root of the unsharded tree holding the same keys. Each shard is locked in turn,
+    /// so concurrent writes may or may not be included.
+    pub fn get_hash(&self) -> Option<Digest> {
+        let workers = thread::available_parallelism().map_or(1, |workers| workers.get());
+        let chunk = self.shards.len().div_ceil(workers);
+        let tops: Vec<Top> = thread::scope(|scope| {
+            let workers: Vec<_> = self
+                .shards
+                .chunks(chunk)
+                .enumerate()
+                .map(|(worker, shards)| {
+                    scope.spawn(move || {
+                        let first = worker * chunk;
+                        shards
+                            .iter()
+                            .enumerate()
+                            .map(|(index, shard)| shard.lock().unwrap().top(&self.prefix_of(first + index), &self.settings))
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect()
+        });
+        match combine(tops, 0, &self.settings) {
+            Top::Empty => None,
+            Top::Leaf(hash, _) | Top::Inner(hash, ..) => Some(hash),
+        }
+    }
+}
+
+// Node at `depth` over the tops of the shards below it. A lone leaf moves up to the
+// shallowest level where it is unique.
+fn combine(mut tops: Vec<Top>, depth: usize, settings: &TreeSettings) -> Top {
+    if tops.len() == 1 {
+        return tops.pop().unwrap();
+    }
+    let right = tops.split_off(tops.len() / 2);
+    let empty = settings.empty_hashes.at_depth(depth + 1).to_vec();
+    let parts = |top: Top| match top {
+        Top::Empty => (empty.clone(), 0, 0),
+        Top::Leaf(hash, count) => (hash, 1, count as u128),
+        Top::Inner(hash, leaves, total) => (hash, leaves, total),
+    };
+    match (combine(tops, depth + 1, settings), combine(right, depth + 1, settings)) {
+        (Top::Empty, Top::Empty) => Top::Empty,
+        (Top::Empty, Top::Leaf(hash, count)) | (Top::Leaf(hash, count), Top::Empty) => Top::Leaf(hash, count),
+        (left, right) => {
+            let ((left, left_leaves, left_total), (right, right_leaves, right_total)) = (parts(left), parts(right));
+            let hash = node_hash(&settings.node_commitment, (&left, left_leaves, left_total), (&right, right_leaves, right_total));
+            Top::Inner(hash, left_leaves + right_leaves, left_total + right_total)
+        }
+    }
+}
@@ -0,0 +1,66 @@
+//! Structural statistics and memory accounting for capacity planning.
+
+use super::*;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TreeStats {
+    pub leaf_nodes: usize,
+    pub inner_nodes: usize,
+    /// Sum of the counts of all leaves.
+    pub total_count: u128,
+    /// Depth of the deepest leaf, the root being at depth 0.
+    pub max_leaf_depth: usize,
+    pub average_leaf_depth: f64,
+    /// Number of leaves at each depth.
+    pub depth_histogram: Vec<usize>,
+    /// Inner nodes with a single child.
+    pub single_child_nodes: usize,
+    /// Maximal runs of consecutive single-child inner nodes.
+    pub single_child_chains: usize,
+    /// Estimated heap usage of all nodes.
+    pub heap_bytes: usize,
+}
+
+impl MerkleTree {
+    pub fn stats(&self) -> TreeStats {
+        let mut stats = TreeStats::default();
+        if let Some(ref root) = self.root {
+            visit(&root.borrow(), 0, false, &mut stats);
+        }
+        if stats.leaf_nodes > 0 {
+            let depth_sum: usize = stats
+                .depth_histogram
+                .iter()
+                .enumerate()
+                .map(|(depth, &leaves)| depth * leaves)
+                .sum();
+            stats.average_leaf_depth = depth_sum as f64 / stats.leaf_nodes as f64;
+        }
+        stats
+    }
+}
+
+fn visit(node: &TreeNode, depth: usize, parent_single_child: bool, stats: &mut TreeStats) {
+    stats.heap_bytes += node.heap_bytes();
+    if node.is_leaf() {
+        stats.leaf_nodes += 1;
+        stats.total_count += node.get_version().unwrap() as u128;
+        stats.max_leaf_depth = std::cmp::max(stats.max_leaf_depth, depth);
+        if stats.depth_histogram.len() <= depth {
+            stats.depth_histogram.resize(depth + 1, 0);
+        }
+        stats.depth_histogram[depth] += 1;
+        return;
+    }
+    stats.inner_nodes += 1;
+    let single_child = node.left.is_none() || node.right.is_none();
+    if single_child {
+        stats.single_child_nodes += 1;
+        if !parent_single_child {
+            stats.single_child_chains += 1;
+        }
+    }
+    for child in [&node.left, &node.right].iter().copied().flatten() {
+        visit(&child.borrow(), depth + 1, single_child, stats);
+    }
+}
@@ -0,0 +1,124 @@
+//! Subtrees addressed by the leading bits of the key hash, for sharding.
+//!
+//! The subtree of a prefix is the node reached by following its bits from the root.
+//! A lone key may sit above the prefix depth, in which case its leaf stands for the
+//! subtree. A shard owner can hold `extract_subtree(prefix)`, serve proofs from it and
+//! turn them into proofs against the global root with `SubtreeProof::stitch`.
+
+use super::*;
+use sync::Summary;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubtreeProof {
+    /// Siblings from the root down the prefix, at most one per prefix bit.
+    pub siblings: Vec<ProofNode>,
+    /// Node where the walk down the prefix ended.
+    pub end: Summary,
+}
+
+impl MerkleTree {
+    /// Hash of the node standing for the subtree, `None` if no key starts with `prefix`.
+    pub fn subtree_hash(&self, prefix: &[u8]) -> Option<Digest> {
+        self.subtree_node(prefix).map(|node| node.borrow().version_hash_raw().clone())
+    }
+
+    /// `None` on an empty tree or one without plain proofs, see `get_proof`.
+    pub fn prove_subtree(&self, prefix: &[u8]) -> Option<SubtreeProof> {
+        if !self.has_proofs(NodeCommitment::Hash) {
+            return None;
+        }
+        let mut node = Rc::clone(self.root_node()?);
+        let mut siblings = vec![];
+        for (level, &bit) in prefix.iter().enumerate() {
+            let next = {
+                let current = node.borrow();
+                if current.is_leaf() {
+                    break;
+                }
+                let (next, alt) = if bit == 0 { (&current.left, &current.right) } else { (&current.right, &current.left) };
+                let sibling = match alt {
+                    Some(alt) => alt.borrow().version_hash_raw().clone(),
+                    None => self.empty_hashes.at_depth(level + 1).to_vec(),
+                };
+                siblings.push(if bit == 1 { ProofNode::Left(sibling) } else { ProofNode::Right(sibling) });
+                match next {
+                    Some(next) => Rc::clone(next),
+                    None => return Some(SubtreeProof { siblings, end: Summary::Empty }),
+                }
+            };
+            node = next;
+        }
+        let node = node.borrow();
+        let end = if node.is_leaf() {
+            Summary::Leaf(node.key_hash_raw().clone(), node.get_version().unwrap())
+        } else {
+            Summary::Inner(node.version_hash_raw().clone())
+        };
+        Some(SubtreeProof { siblings, end })
+    }
+
+    /// Tree of the keys starting with `prefix`, sharing its nodes with `self`. Its subtree
+    /// at `prefix` hashes as in `self`.
+    pub fn extract_subtree(&self, prefix: &[u8]) -> MerkleTree {
+        let mut tree = self.share();
+        tree.root = self.subtree_node(prefix).map(|node| {
+            if node.borrow().is_leaf() {
+                node
+            } else {
+                chain_to(node, prefix)
+            }
+        });
+        tree.flush();
+        tree
+    }
+}
+
+impl SubtreeProof {
+    pub fn verify(&self, root: &[u8], prefix: &[u8], subtree_hash: Option<&[u8]>) -> bool {
+        self.verify_with(root, prefix, subtree_hash, &EmptyHashes::Constant)
+    }
+
+    /// Checks that the subtree at `prefix` under `root` hashes to `subtree_hash`, `None`
+    /// meaning that no key starts with `prefix`.
+    pub fn verify_with(&self, root: &[u8], prefix: &[u8], subtree_hash: Option<&[u8]>, empty: &EmptyHashes) -> bool {
+        let depth = self.siblings.len();
+        if depth > prefix.len() || !follows_prefix(&self.siblings, prefix) {
+            return false;
+        }
+        let (node_hash, proven) = match self.end {
+            Summary::Inner(ref hash) if depth == prefix.len() => (hash.clone(), Some(hash.clone())),
+            Summary::Inner(_) => return false,
+            Summary::Leaf(ref key_hash, count) => {
+                if key_hash.len() * 8 < prefix.len() {
+                    return false;
+                }
+                let key_hash = HashBytes::new(key_hash.clone());
+                let leaf_hash = hash_from_version(count, &key_hash).bytes().clone();
+                // A leaf off the prefix shows that no key starts with it
+                let on_prefix = (0..prefix.len()).all(|level| key_hash.bit(level) == prefix[level]);
+                (leaf_hash.clone(), if on_prefix { Some(leaf_hash) } else { None })
+            }
+            Summary::Empty => (empty.at_depth(depth).to_vec(), None),
+        };
+        proven.as_deref() == subtree_hash && roothash_from_siblings(&mut self.siblings.clone(), node_hash) == root
+    }
+
+    /// Proof against the global root from a proof served by `extract_subtree(prefix)`,
+    /// for a key starting with `prefix`.
+    pub fn stitch(&self, shard_proof: &[ProofNode]) -> Vec<ProofNode> {
+        // Below a lone leaf the shard tree is that leaf, otherwise its proofs first run
+        // down the single-child chain standing for the prefix
+        let skip = if shard_proof.len() > self.siblings.len() { self.siblings.len() } else { 0 };
+        let mut proof = self.siblings.clone();
+        proof.extend_from_slice(&shard_proof[skip..]);
+        proof
+    }
+}
+
+fn follows_prefix(siblings: &[ProofNode], prefix: &[u8]) -> bool {
+    siblings.iter().zip(prefix).all(|(node, &bit)| match node {
+        ProofNode::Left(_) => bit == 1,
+        ProofNode::Right(_) => bit == 0,
+        _ => false,
+    })
+}
@@ -0,0 +1,86 @@
+//! Proofs for trees whose inner nodes commit to the counts below them.
+//!
+//! Under `NodeCommitment::Sum` every inner hash covers the totals of both children, so
+//! the root commits to the total count. A sum proof is a plain proof together with the
+//! total below each sibling, which lets a client check that its count is part of a
+//! published root and total.
+
+use super::*;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SumProof {
+    /// Proof of the key, as returned by `get_proof`.
+    pub path: Vec<ProofNode>,
+    /// Total below each sibling of `path`, root first.
+    pub sibling_totals: Vec<u128>,
+}
+
+impl MerkleTree {
+    /// Sum of all counts, 0 for an empty tree.
+    pub fn get_total(&self) -> u128 {
+        self.root_node().map_or(0, |node| node.borrow().total())
+    }
+
+    /// Proof of the key for a tree built with `NodeCommitment::Sum`. Panics on an empty tree,
+    /// one with hash-chain leaves or one built otherwise.
+    pub fn get_sum_proof<T: Hashable + Debug>(&self, key: T) -> SumProof {
+        assert!(self.has_proofs(NodeCommitment::Sum), "tree has no sum proofs");
+        let hash = self.hash_key(&key);
+        let root = self.root_node().expect("proof from an empty tree");
+        let path = root.borrow().get_proof_hash(&hash, &self.empty_hashes);
+        let sibling_totals = path_siblings(root, &hash, &self.empty_hashes)
+            .iter()
+            .map(|sibling| sibling.as_ref().map_or(0, |sibling| sibling.borrow().total()))
+            .collect();
+        SumProof { path, sibling_totals }
+    }
+}
+
+pub fn verify_sum_proof<T: Hashable>(root: &[u8], total: u128, key: T, proof: &SumProof) -> Option<u64> {
+    verify_sum_proof_with(root, total, key, proof, &EmptyHashes::Constant)
+}
+
+/// Count the proof shows for the key under a root committing to `total`, 0 meaning absent.
+/// None if the proof is invalid.
+pub fn verify_sum_proof_with<T: Hashable>(
+    root: &[u8],
+    total: u128,
+    key: T,
+    proof: &SumProof,
+    empty: &EmptyHashes,
+) -> Option<u64> {
+    let key_hash = key.hash();
+    let (terminal, siblings) = proof.path.split_last()?;
+    if siblings.len() != proof.sibling_totals.len() || !follows_key_path(siblings, &key_hash) {
+        return None;
+    }
+    let (mut hash, mut sum, count) = match terminal {
+        ProofNode::Leaf(leaf_hash, count) => {
+            if leaf_hash.len() != key_hash.bytes().len() {
+                return None;
+            }
+            let leaf = HashBytes::new(leaf_hash.clone());
+            // A different leaf shows absence only if it occupies the key's path so far
+            if leaf != key_hash && (0..siblings.len()).any(|level| leaf.bit(level) != key_hash.bit(level)) {
+                return None;
+            }
+            let shown = if leaf == key_hash { *count } else { 0 };
+            (hash_from_version(*count, &leaf).bytes().clone(), *count as u128, shown)
+        }
+        ProofNode::None => (empty.at_depth(siblings.len()).to_vec(), 0, 0),
+        _ => return None,
+    };
+    for (sibling, &sibling_total) in siblings.iter().zip(&proof.sibling_totals).rev() {
+        hash = match sibling {
+            ProofNode::Left(left) => sum_node_hash(left, sibling_total, &hash, sum),
+            ProofNode::Right(right) => sum_node_hash(&hash, sum, right, sibling_total),
+            _ => return None,
+        };
+        sum = sum.checked_add(sibling_total)?;
+    }
+    if hash == root && sum == total {
+        Some(count)
+    } else {
+        None
+    }
+}
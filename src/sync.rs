@@ -0,0 +1,339 @@
+//! Anti-entropy synchronization between two replicas of a `MerkleTree`.
+//!
+//! The initiator walks both trees level by level, asking the responder for the
+//! summaries of the subtrees whose hashes disagree. Once a differing subtree holds
+//! at most one leaf on either side, the remaining leaves are exchanged directly.
+//! Counts are merged by taking the maximum, so both replicas end up with the same root.
+
+use super::*;
+use std::collections::VecDeque;
+
+/// Bit path from the root, one `0` (left) or `1` (right) per level.
+pub type Prefix = Vec<u8>;
+
+/// Key hash of a leaf together with its count.
+pub type LeafEntry = (Vec<u8>, u64);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Summary {
+    Empty,
+    Leaf(Vec<u8>, u64),
+    Inner(Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyncMessage {
+    GetSummaries(Vec<Prefix>),
+    Summaries(Vec<(Prefix, Summary)>),
+    /// `push` holds leaves the responder is missing, `pull` lists prefixes where the
+    /// initiator holds at most the given leaf and wants everything better.
+    Reconcile {
+        push: Vec<LeafEntry>,
+        pull: Vec<(Prefix, Option<LeafEntry>)>,
+    },
+    Leaves(Vec<LeafEntry>),
+    Done,
+}
+
+/// The peer broke the protocol; the session is over and the tree keeps what it merged so far.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyncError {
+    /// A message that does not fit the state of the session.
+    UnexpectedMessage,
+    /// Summaries that do not answer the prefixes asked for.
+    SummaryMismatch,
+    /// A prefix or key hash of the wrong shape, or a count the tree cannot hold.
+    Malformed,
+}
+
+pub trait Transport {
+    fn send(&mut self, msg: SyncMessage);
+    fn recv(&mut self) -> Option<SyncMessage>;
+}
+
+/// In-process transport; `pair` returns the two connected ends.
+pub struct LoopbackTransport {
+    inbox: Rc<RefCell<VecDeque<SyncMessage>>>,
+    outbox: Rc<RefCell<VecDeque<SyncMessage>>>,
+}
+
+impl LoopbackTransport {
+    pub fn pair() -> (Self, Self) {
+        let (a, b) = (Rc::new(RefCell::new(VecDeque::new())), Rc::new(RefCell::new(VecDeque::new())));
+        (
+            LoopbackTransport { inbox: Rc::clone(&a), outbox: Rc::clone(&b) },
+            LoopbackTransport { inbox: b, outbox: a },
+        )
+    }
+}
+
+impl Transport for LoopbackTransport {
+    fn send(&mut self, msg: SyncMessage) {
+        self.outbox.borrow_mut().push_back(msg);
+    }
+
+    fn recv(&mut self) -> Option<SyncMessage> {
+        self.inbox.borrow_mut().pop_front()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum SyncState {
+    Idle,
+    AwaitingSummaries(Vec<Prefix>),
+    AwaitingLeaves,
+    Finished,
+}
+
+pub struct SyncSession {
+    state: SyncState,
+    push: Vec<LeafEntry>,
+    pull: Vec<(Prefix, Option<LeafEntry>)>,
+    pulled: Vec<LeafEntry>,
+    transferred: usize,
+}
+
+impl Default for SyncSession {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SyncSession {
+    pub fn new() -> Self {
+        SyncSession {
+            state: SyncState::Idle,
+            push: vec![],
+            pull: vec![],
+            pulled: vec![],
+            transferred: 0,
+        }
+    }
+
+    /// Opens the exchange from the initiator side.
+    pub fn start(&mut self) -> SyncMessage {
+        let root: Prefix = vec![];
+        self.state = SyncState::AwaitingSummaries(vec![root.clone()]);
+        SyncMessage::GetSummaries(vec![root])
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state == SyncState::Finished
+    }
+
+    /// Number of leaves sent or received by this side.
+    pub fn transferred(&self) -> usize {
+        self.transferred
+    }
+
+    /// Advances the state machine; returns the reply to send, if any.
+    pub fn handle(&mut self, tree: &mut MerkleTree, msg: SyncMessage) -> Result<Option<SyncMessage>, SyncError> {
+        if !well_formed(&msg, tree.leaf_commitment.max_count()) {
+            self.state = SyncState::Finished;
+            return Err(SyncError::Malformed);
+        }
+        let reply = self.handle_checked(tree, msg);
+        if reply.is_err() {
+            self.state = SyncState::Finished;
+        }
+        reply
+    }
+
+    fn handle_checked(&mut self, tree: &mut MerkleTree, msg: SyncMessage) -> Result<Option<SyncMessage>, SyncError> {
+        Ok(match msg {
+            // Responder side
+            SyncMessage::GetSummaries(prefixes) => {
+                let summaries = prefixes
+                    .into_iter()
+                    .map(|prefix| {
+                        let summary = summary_at(tree, &prefix);
+                        (prefix, summary)
+                    })
+                    .collect();
+                Some(SyncMessage::Summaries(summaries))
+            }
+            SyncMessage::Reconcile { push, pull } => {
+                self.transferred += push.len();
+                let mut reply = vec![];
+                for (prefix, theirs) in pull {
+                    for (hash, count) in leaves_at(tree, &prefix) {
+                        let their_count = match theirs {
+                            Some((ref their_hash, their_count)) if *their_hash == hash => their_count,
+                            _ => 0,
+                        };
+                        if count > their_count {
+                            reply.push((hash, count));
+                        }
+                    }
+                    if let Some(leaf) = theirs {
+                        merge_leaf(tree, leaf);
+                    }
+                }
+                for leaf in push {
+                    merge_leaf(tree, leaf);
+                }
+                self.transferred += reply.len();
+                Some(SyncMessage::Leaves(reply))
+            }
+            SyncMessage::Done => {
+                self.state = SyncState::Finished;
+                None
+            }
+            // Initiator side
+            SyncMessage::Summaries(summaries) => {
+                let expected = match std::mem::replace(&mut self.state, SyncState::Idle) {
+                    SyncState::AwaitingSummaries(expected) => expected,
+                    _ => return Err(SyncError::UnexpectedMessage),
+                };
+                if expected.len() != summaries.len() || expected.iter().zip(&summaries).any(|(asked, (prefix, _))| asked != prefix) {
+                    return Err(SyncError::SummaryMismatch);
+                }
+                let mut next_level = vec![];
+                for (prefix, theirs) in summaries {
+                    let ours = summary_at(tree, &prefix);
+                    if ours == theirs {
+                        continue;
+                    }
+                    match (&ours, &theirs) {
+                        (Summary::Inner(_), Summary::Inner(_)) => {
+                            for bit in 0..2 {
+                                let mut child = prefix.clone();
+                                child.push(bit);
+                                next_level.push(child);
+                            }
+                        }
+                        (_, Summary::Empty) => self.push.extend(leaves_at(tree, &prefix)),
+                        (_, Summary::Leaf(their_hash, their_count)) => {
+                            let mut our_count = 0;
+                            for (hash, count) in leaves_at(tree, &prefix) {
+                                if hash == *their_hash {
+                                    our_count = count;
+                                    if count <= *their_count {
+                                        continue;
+                                    }
+                                }
+                                self.push.push((hash, count));
+                            }
+                            if *their_count > our_count {
+                                self.pulled.push((their_hash.clone(), *their_count));
+                            }
+                        }
+                        (_, Summary::Inner(_)) => {
+                            let ours = leaves_at(tree, &prefix).pop();
+                            self.pull.push((prefix, ours));
+                        }
+                    }
+                }
+                if !next_level.is_empty() {
+                    self.state = SyncState::AwaitingSummaries(next_level.clone());
+                    return Ok(Some(SyncMessage::GetSummaries(next_level)));
+                }
+                if self.push.is_empty() && self.pull.is_empty() {
+                    self.apply(tree, vec![]);
+                    return Ok(Some(SyncMessage::Done));
+                }
+                self.transferred += self.push.len();
+                self.state = SyncState::AwaitingLeaves;
+                Some(SyncMessage::Reconcile {
+                    push: std::mem::take(&mut self.push),
+                    pull: std::mem::take(&mut self.pull),
+                })
+            }
+            SyncMessage::Leaves(leaves) => {
+                match std::mem::replace(&mut self.state, SyncState::Idle) {
+                    SyncState::AwaitingLeaves => {}
+                    _ => return Err(SyncError::UnexpectedMessage),
+                }
+                self.apply(tree, leaves);
+                Some(SyncMessage::Done)
+            }
+        })
+    }
+
+    // Merges the received leaves together with those pulled from single-leaf summaries
+    fn apply(&mut self, tree: &mut MerkleTree, leaves: Vec<LeafEntry>) {
+        let mut leaves = leaves;
+        leaves.append(&mut self.pulled);
+        self.transferred += leaves.len();
+        for leaf in leaves {
+            merge_leaf(tree, leaf);
+        }
+        self.state = SyncState::Finished;
+    }
+}
+
+// Prefixes have one 0 or 1 per level and key hashes the full hash length, so that
+// nothing a peer sends can make the walks below index past a hash
+fn well_formed(msg: &SyncMessage, max_count: u64) -> bool {
+    let prefix_ok = |prefix: &Prefix| prefix.len() <= HASH_LENGTH && prefix.iter().all(|&bit| bit <= 1);
+    let leaf_ok = |(hash, count): &LeafEntry| hash.len() * 8 == HASH_LENGTH && *count <= max_count;
+    match msg {
+        SyncMessage::GetSummaries(prefixes) => prefixes.iter().all(prefix_ok),
+        SyncMessage::Summaries(summaries) => summaries.iter().all(|(prefix, summary)| {
+            prefix_ok(prefix)
+                && match summary {
+                    Summary::Leaf(hash, count) => hash.len() * 8 == HASH_LENGTH && *count <= max_count,
+                    _ => true,
+                }
+        }),
+        SyncMessage::Reconcile { push, pull } => {
+            push.iter().all(leaf_ok) && pull.iter().all(|(prefix, leaf)| prefix_ok(prefix) && leaf.iter().all(leaf_ok))
+        }
+        SyncMessage::Leaves(leaves) => leaves.iter().all(leaf_ok),
+        SyncMessage::Done => true,
+    }
+}
+
+fn summary_at(tree: &MerkleTree, prefix: &[u8]) -> Summary {
+    match tree.subtree_node(prefix) {
+        Some(node) => {
+            let node = node.borrow();
+            if node.is_leaf() {
+                Summary::Leaf(node.key_hash_raw().clone(), node.get_version().unwrap())
+            } else {
+                Summary::Inner(node.version_hash_raw().clone())
+            }
+        }
+        None => Summary::Empty,
+    }
+}
+
+fn leaves_at(tree: &MerkleTree, prefix: &[u8]) -> Vec<LeafEntry> {
+    let mut leaves = vec![];
+    if let Some(node) = tree.subtree_node(prefix) {
+        node.borrow().collect_leaves(&mut leaves);
+    }
+    leaves
+        .into_iter()
+        .map(|(hash, count)| (hash.bytes().clone(), count))
+        .collect()
+}
+
+// Counts past what the tree can hold were turned away by `well_formed`
+fn merge_leaf(tree: &mut MerkleTree, (hash, count): LeafEntry) {
+    let _ = tree.update_count(HashBytes::new(hash), |ours| Some(std::cmp::max(ours, count)));
+}
+
+/// Runs a full session between two local trees over a loopback transport.
+/// Returns the number of messages exchanged.
+pub fn sync_loopback(initiator: &mut MerkleTree, responder: &mut MerkleTree) -> Result<usize, SyncError> {
+    let (mut near, mut far) = LoopbackTransport::pair();
+    let (mut local, mut remote) = (SyncSession::new(), SyncSession::new());
+    near.send(local.start());
+    let mut messages = 1;
+    while !(local.is_finished() && remote.is_finished()) {
+        if let Some(msg) = far.recv() {
+            if let Some(reply) = remote.handle(responder, msg)? {
+                far.send(reply);
+                messages += 1;
+            }
+        }
+        if let Some(msg) = near.recv() {
+            if let Some(reply) = local.handle(initiator, msg)? {
+                near.send(reply);
+                messages += 1;
+            }
+        }
+    }
+    Ok(messages)
+}
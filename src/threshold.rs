@@ -0,0 +1,58 @@
+//! Proofs that a key was counted at least k times, without revealing the count.
+//!
+//! Needs a tree built with `LeafCommitment::HashChain`, whose leaves commit to the
+//! link `C = H^count(seed)`. Revealing the 32-byte `w = H^(count - k)(seed)` lets anyone
+//! check `H^k(w) == C`, while claiming more than the count would take a 32-byte preimage
+//! of the seed. The seed is an HMAC output, so even the prover only knows a longer
+//! preimage of it. Soundness relies on the seed being derived honestly from the tree
+//! secret, which an auditor holding that secret can check.
+
+use super::*;
+use treenode::hash_structures::{chain_leaf_hash, chain_seed, hash_chain, MAX_CHAIN_COUNT};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThresholdProof {
+    /// Siblings from the root down to the leaf.
+    pub siblings: Vec<ProofNode>,
+    /// Chain link committed in the leaf.
+    pub link: Digest,
+    pub witness: Digest,
+}
+
+impl MerkleTree {
+    /// `None` unless the tree uses hash-chain leaves and the key's count is at least `k`.
+    pub fn prove_count_at_least<T: Hashable + Debug>(&self, key: T, k: u64) -> Option<ThresholdProof> {
+        let secret = match self.leaf_commitment {
+            LeafCommitment::HashChain(ref secret) => secret,
+            LeafCommitment::Count => return None,
+        };
+        let hash = self.hash_key(&key);
+        let mut siblings = self.root_node()?.borrow().get_proof_hash(&hash, &self.empty_hashes);
+        siblings.pop();
+        let prefix: Vec<u8> = (0..siblings.len()).map(|level| hash.bit(level)).collect();
+        let leaf = self.subtree_node(&prefix)?;
+        let leaf = leaf.borrow();
+        let count = leaf.get_version()?;
+        if count < k || leaf.key_hash_raw() != hash.bytes() {
+            return None;
+        }
+        Some(ThresholdProof {
+            siblings,
+            link: leaf.get_chain_link()?.clone(),
+            witness: hash_chain(&chain_seed(secret, &hash), count - k),
+        })
+    }
+}
+
+pub fn verify_count_at_least<T: Hashable>(root: &[u8], key: T, k: u64, proof: &ThresholdProof) -> bool {
+    let key_hash = key.hash();
+    // Links are single hash outputs; anything longer could be a preimage of the seed
+    if proof.witness.len() * 8 != HASH_LENGTH || k > MAX_CHAIN_COUNT || !follows_key_path(&proof.siblings, &key_hash) {
+        return false;
+    }
+    if hash_chain(&proof.witness, k) != proof.link {
+        return false;
+    }
+    let leaf_hash = chain_leaf_hash(key_hash.bytes(), &proof.link).bytes().clone();
+    roothash_from_siblings(&mut proof.siblings.clone(), leaf_hash) == root
+}
@@ -0,0 +1,60 @@
+//! Groups of inserts that are applied atomically or not at all.
+//!
+//! The transaction works on a snapshot that shares every node with the tree.
+//! Inserts copy only the nodes on their path, so the original structure stays
+//! untouched until `commit` swaps the root in.
+
+use super::*;
+
+pub struct Transaction<'a> {
+    tree: &'a mut MerkleTree,
+    scratch: MerkleTree,
+    pending: usize,
+}
+
+impl MerkleTree {
+    pub fn transaction(&mut self) -> Transaction<'_> {
+        let scratch = self.share();
+        Transaction { tree: self, scratch, pending: 0 }
+    }
+}
+
+impl<'a> Transaction<'a> {
+    pub fn insert<T: Hashable + Debug>(&mut self, key: T) {
+        self.scratch.insert(key);
+        self.pending += 1;
+    }
+
+    /// Count of the key including the inserts of this transaction.
+    pub fn contains<T: Hashable + Debug>(&self, key: T) -> Option<u64> {
+        match self.scratch.root {
+            Some(ref node) => node.borrow().contains(&self.scratch.hash_key(&key)),
+            None => None,
+        }
+    }
+
+    /// Root the tree will have if the transaction is committed.
+    pub fn get_hash(&self) -> Option<Vec<u8>> {
+        self.scratch.get_hash()
+    }
+
+    /// Number of inserts buffered so far.
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    pub fn commit(mut self) {
+        self.tree.root = self.scratch.root.take();
+    }
+
+    /// Commits and records the new root in `log` as a batch of the buffered inserts;
+    /// returns the sequence number of the record.
+    pub fn commit_logged(self, log: &mut root_log::RootLog) -> u64 {
+        let operation = root_log::Operation::Batch(self.pending);
+        self.tree.root = self.scratch.root;
+        log.record(self.tree, operation)
+    }
+
+    /// Drops the buffered inserts; dropping the transaction has the same effect.
+    pub fn rollback(self) {}
+}
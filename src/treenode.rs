@@ -1,15 +1,25 @@
 use super::*;
+use crate::absence_proof::NonMembershipProof;
 use std::mem;
 
 pub mod hash_structures;
 use hash_structures::*;
 
-const HASH_LENGTH: usize = 256;
-const DEFAULT_HASH: &[u8] = &[1; 32];
+pub(crate) const HASH_LENGTH: usize = 256;
+pub(crate) const DEFAULT_HASH: &[u8] = &[1; 32];
+/// Largest count the original `i32` leaf encoding could hold.
+pub const LEGACY_MAX_COUNT: u64 = i32::MAX as u64;
+const WIDE_LEAF_ENCODING: u8 = 2;
 
 pub(super) struct TreeNode {
     entry: NodeKind,
     version: Option<Version>,
+    /// Inner node whose hash is stale until `rehash`.
+    dirty: bool,
+    /// Sum of the counts below an inner node, kept up to date by `rehash`.
+    total: u128,
+    /// Number of leaves below an inner node, kept up to date by `rehash`.
+    leaves: u64,
     pub(super) left: Option<Rc<RefCell<TreeNode>>>,
     pub(super) right: Option<Rc<RefCell<TreeNode>>>,
 }
@@ -19,28 +29,70 @@ impl TreeNode {
         TreeNode {
             entry: NodeKind::Unassigned,
             version: None,
+            dirty: false,
+            total: 0,
+            leaves: 0,
             left: None,
             right: None,
         }
     }
     //TODO take care of ccar
-    pub(super) fn new_leaf(hashbytes: HashBytes) -> Self {
+    pub(super) fn new_committed_leaf(hashbytes: HashBytes, count: u64, commitment: &LeafCommitment) -> Self {
         TreeNode {
-            version: Some(Version::new(1, &hashbytes)),
+            version: Some(Version::committed(count, &hashbytes, commitment)),
             entry: NodeKind::Leaf(hashbytes),
+            dirty: false,
+            total: 0,
+            leaves: 0,
             left: None,
             right: None,
         }
     }
 
+    pub(super) fn new_inner(
+        hashbytes: HashBytes,
+        left: Option<Rc<RefCell<TreeNode>>>,
+        right: Option<Rc<RefCell<TreeNode>>>,
+    ) -> Self {
+        let children = [&left, &right];
+        let total = children.iter().copied().flatten().map(|child| child.borrow().total()).sum();
+        let leaves = children.iter().copied().flatten().map(|child| child.borrow().leaf_count()).sum();
+        TreeNode {
+            entry: NodeKind::Inner(hashbytes),
+            version: None,
+            dirty: false,
+            total,
+            leaves,
+            left,
+            right,
+        }
+    }
+
     pub(super) fn key_hash_raw(&self) -> &Vec<u8> {
         match self.entry {
-            NodeKind::Leaf(ref hashbytes) => &hashbytes.bytes(),
-            NodeKind::Inner(ref hashbytes) => &hashbytes.bytes(),
+            NodeKind::Leaf(ref hashbytes) => hashbytes.bytes(),
+            NodeKind::Inner(ref hashbytes) => hashbytes.bytes(),
             _ => panic!("NodeKind::Unassigned does not contain hashbytes"),
         }
     }
 
+    // Copy of the node that still shares its children
+    fn shallow_clone(&self) -> Self {
+        TreeNode {
+            entry: self.entry.clone(),
+            version: self.version.clone(),
+            dirty: self.dirty,
+            total: self.total,
+            leaves: self.leaves,
+            left: self.left.clone(),
+            right: self.right.clone(),
+        }
+    }
+
+    pub(super) fn is_leaf(&self) -> bool {
+        self.entry.is_leaf()
+    }
+
     pub(super) fn version_hash_raw(&self) -> &Vec<u8> {
         match self.version {
             Some(ref version) => version.hash.bytes(),
@@ -48,25 +100,53 @@ impl TreeNode {
         }
     }
 
-    pub(super) fn insert<T: Hashable + Debug>(&mut self, key: T) {
-        let hashbits = key.hash();
-        self.insert_with_level(hashbits, 0);
+    // Marks an inner node whose hash has to be recomputed by `rehash`
+    fn mark_dirty(&mut self) {
+        if !matches!(self.entry, NodeKind::Inner(_)) {
+            self.entry = NodeKind::Inner(HashBytes::new(vec![]));
+        }
+        self.dirty = true;
+    }
+
+    // Recomputes the cached fields of a dirty node whose children are up to date
+    fn refresh(&mut self, level: usize, empty: &EmptyHashes, nodes: &NodeCommitment) {
+        let children = [&self.left, &self.right];
+        self.total = children.iter().copied().flatten().map(|child| child.borrow().total()).sum();
+        self.leaves = children.iter().copied().flatten().map(|child| child.borrow().leaf_count()).sum();
+        self.entry = NodeKind::Inner(hash_from_children(&self.left, &self.right, level, empty, nodes));
+        self.dirty = false;
     }
 
-    fn update_version(&mut self) {
+    fn update_version(&mut self, value: u64, commitment: &LeafCommitment) {
         if let Some(ref mut vers) = self.version {
-            vers.value += 1;
-            vers.hash = hash_from_version(vers.value, self.entry.get_hash());
+            vers.set(value, self.entry.get_hash(), commitment);
         } else {
             panic!("version not available to update")
         }
     }
 
-    fn get_version(&self) -> Option<i32> {
-        if let Some(ref vers) = self.version {
-            Some(vers.value)
+    pub(super) fn get_chain_link(&self) -> Option<&Vec<u8>> {
+        self.version.as_ref().and_then(|vers| vers.chain.as_ref())
+    }
+
+    pub(super) fn get_version(&self) -> Option<u64> {
+        self.version.as_ref().map(|vers| vers.value)
+    }
+
+    /// Sum of the counts in the subtree.
+    pub(super) fn total(&self) -> u128 {
+        match self.get_version() {
+            Some(count) => count as u128,
+            None => self.total,
+        }
+    }
+
+    /// Number of leaves in the subtree.
+    pub(super) fn leaf_count(&self) -> u64 {
+        if self.is_leaf() {
+            1
         } else {
-            None
+            self.leaves
         }
     }
 
@@ -74,103 +154,101 @@ impl TreeNode {
         TreeNode {
             entry: luggage.0,
             version: luggage.1,
+            dirty: false,
+            total: 0,
+            leaves: 0,
             left: None,
             right: None,
         }
     }
 
-    fn insert_with_level(&mut self, hash_to_insert: HashBytes, level: usize) {
-        // Choose left or right
-        let (next_child, _) = next_child(&mut self.left, &mut self.right, &hash_to_insert, level);
-
-        match next_child {
-            Some(ref node) => {
-                // Next is non-empty
-                node.borrow_mut()
-                    .insert_with_level(hash_to_insert, level + 1);
-            }
-            None => {
-                // We are in a leaf equal to which we want to add
-                if let NodeKind::Leaf(ref curhash) = self.entry {
-                    if curhash.bytes() == hash_to_insert.bytes() {
-                        self.update_version();
-                        return;
-                    }
-                }
-                if self.entry.is_leaf() {
-                    // We are in a leaf. Move the hash out of the self.entry and replace it with an unassigned NodeKind
-                    let entry = mem::replace(&mut self.entry, NodeKind::Unassigned);
-                    let version = self.version.take();
-                    self.insert_with_luggage(hash_to_insert, level, (entry, version));
-                } else {
-                    // We are in an Inner node and next is None
-                    *next_child = Some(Rc::new(RefCell::new(TreeNode::new_leaf(hash_to_insert))));
-                }
-            }
+    // Turns this leaf into an inner node holding both keys. Single-child nodes are chained
+    // down to the level where the two paths part, built bottom up.
+    fn split_leaf(&mut self, hash_to_insert: &HashBytes, level: usize, count: u64, commitment: &LeafCommitment) {
+        let entry = mem::replace(&mut self.entry, NodeKind::Unassigned);
+        let version = self.version.take();
+        let other = entry.get_hash().clone();
+        let mut split = level;
+        while split + 1 < HASH_LENGTH && other.bit(split) == hash_to_insert.bit(split) {
+            split += 1;
         }
-        // Backtrack
-        let new_hashbytes = hash_from_children(&self.left, &self.right);
-        self.entry = NodeKind::Inner(new_hashbytes);
-    }
 
-    fn insert_with_luggage(
-        &mut self,
-        hash_to_insert: HashBytes,
-        level: usize,
-        luggage: (NodeKind, Option<Version>),
-    ) {
-        let (next_child, alt_child) =
-            next_child(&mut self.left, &mut self.right, &hash_to_insert, level);
-
-        //If there is a luggage (next has to be empty)
-        if luggage.0.get_hash().bit(level) != hash_to_insert.bit(level) {
-            *next_child = Some(Rc::new(RefCell::new(TreeNode::new_leaf(hash_to_insert))));
-            *alt_child = Some(Rc::new(RefCell::new(TreeNode::new_from_luggage(luggage))));
-            //*alt_child = Some(Rc::new(RefCell::new(TreeNode::new_leaf(luggage))));
-        } else {
-            let mut nextnode = TreeNode::new();
-            nextnode.insert_with_luggage(hash_to_insert, level + 1, luggage);
-            *next_child = Some(Rc::new(RefCell::new(nextnode)));
+        let mut node = TreeNode::new();
+        let (new_slot, moved_slot) = next_child(&mut node.left, &mut node.right, hash_to_insert, split);
+        let leaf = TreeNode::new_committed_leaf(hash_to_insert.clone(), count, commitment);
+        *new_slot = Some(Rc::new(RefCell::new(leaf)));
+        *moved_slot = Some(Rc::new(RefCell::new(TreeNode::new_from_luggage((entry, version)))));
+        node.mark_dirty();
+        for chain_level in (level..split).rev() {
+            let mut parent = TreeNode::new();
+            let (next_child, _) = next_child(&mut parent.left, &mut parent.right, hash_to_insert, chain_level);
+            *next_child = Some(Rc::new(RefCell::new(node)));
+            parent.mark_dirty();
+            node = parent;
         }
-        // Backtrack
-        let new_hashbytes = hash_from_children(&self.left, &self.right);
-        self.entry = NodeKind::Inner(new_hashbytes);
+        *self = node;
     }
 
-    pub(super) fn contains<T: Hashable + Debug>(&self, key: T) -> Option<i32> {
-        let hash_bits = key.hash();
+    pub(super) fn contains(&self, hash_bits: &HashBytes) -> Option<u64> {
+        // The proof is discarded, so the empty hashes it holds do not matter
         let mut proof = vec![];
-        self.contains_hash(&hash_bits, 0,  &mut proof)
+        self.contains_hash(hash_bits, &mut proof, &EmptyHashes::Constant)
     }
 
-    pub(super) fn get_proof<T: Hashable + Debug>(&self, key: T) -> Vec<ProofNode> {
-        let hash_bits = key.hash();
+    pub(super) fn get_proof_hash(&self, hash_bits: &HashBytes, empty: &EmptyHashes) -> Vec<ProofNode> {
         let mut proof = vec![];
-        self.contains_hash(&hash_bits, 0,  &mut proof);
+        self.contains_hash(hash_bits, &mut proof, empty);
+        // With full-depth leaves the path of an absent key runs on through empty subtrees
+        // down to the leaf level, as in a standard sparse Merkle proof
+        if empty.full_depth() && proof.last() == Some(&ProofNode::None) {
+            proof.pop();
+            push_empty_siblings(&mut proof, hash_bits, empty);
+            proof.push(ProofNode::None);
+        }
         proof
     }
 
-    fn contains_hash(&self, hash_to_check: &HashBytes, level: usize, proof: &mut Vec<ProofNode>) -> Option<i32> {
-        if level >= HASH_LENGTH {
-            //This will happen with almost 0 probability
-            panic!();
+    // Follows the path of the hash from this node, pushing the proof as it goes.
+    // Only one node is borrowed at a time.
+    fn contains_hash(&self, hash_to_check: &HashBytes, proof: &mut Vec<ProofNode>, empty: &EmptyHashes) -> Option<u64> {
+        let mut step = self.contains_step(hash_to_check, 0, proof, empty);
+        let mut level = 1;
+        loop {
+            match step {
+                Lookup::Descend(node) => step = node.borrow().contains_step(hash_to_check, level, proof, empty),
+                Lookup::Done(count) => return count,
+            }
+            level += 1;
         }
+    }
 
+    fn contains_step(
+        &self,
+        hash_to_check: &HashBytes,
+        level: usize,
+        proof: &mut Vec<ProofNode>,
+        empty: &EmptyHashes,
+    ) -> Lookup {
         if let NodeKind::Leaf(hashbytes) = &self.entry {
             proof.push(ProofNode::Leaf(self.key_hash_raw().clone(), self.get_version().unwrap()));
             if hashbytes.bytes() == hash_to_check.bytes() {
-                return self.get_version();
+                return Lookup::Done(self.get_version());
             } else {
-                return None;
+                return Lookup::Done(None);
             }
         }
 
-        let (next_child, alt_child) = next_child(&self.left, &self.right, &hash_to_check, level);
+        // Distinct hashes part by their last bit at the latest, so only leaves sit this deep
+        if level >= HASH_LENGTH {
+            return Lookup::Done(None);
+        }
+
+        let (next_child, alt_child) = next_child(&self.left, &self.right, hash_to_check, level);
 
         let branch_hash = if let Some(alt_child) = alt_child {
             alt_child.borrow().version_hash_raw().clone()
         } else {
-            DEFAULT_HASH.to_vec()
+            empty.at_depth(level + 1).to_vec()
         };
 
         if hash_to_check.bit(level) == 1 {
@@ -180,13 +258,268 @@ impl TreeNode {
         }
 
         match next_child {
-            Some(ref node) => node.borrow().contains_hash(hash_to_check, level + 1, proof),
+            Some(ref node) => Lookup::Descend(Rc::clone(node)),
             None => {
                 proof.push(ProofNode::None);
-                None
+                Lookup::Done(None)
             },
         }
     }
+
+    // Bytes allocated for this node alone: the Rc allocation and its hash buffers
+    pub(super) fn heap_bytes(&self) -> usize {
+        let hashes = match self.entry {
+            NodeKind::Leaf(ref hashbytes) | NodeKind::Inner(ref hashbytes) => hashbytes.bytes().capacity(),
+            NodeKind::Unassigned => 0,
+        };
+        let version = self.version.as_ref().map_or(0, |vers| vers.hash.bytes().capacity());
+        2 * mem::size_of::<usize>() + mem::size_of::<RefCell<TreeNode>>() + hashes + version
+    }
+
+    // Appends every leaf of the subtree as (key hash, count)
+    pub(super) fn collect_leaves(&self, leaves: &mut Vec<(HashBytes, u64)>) {
+        if let NodeKind::Leaf(ref hashbytes) = self.entry {
+            leaves.push((hashbytes.clone(), self.get_version().unwrap()));
+            return;
+        }
+        for child in [&self.left, &self.right].iter().copied().flatten() {
+            child.borrow().collect_leaves(leaves);
+        }
+    }
+}
+
+// Copies a node shared with another version of the tree so it can be mutated in place.
+pub(super) fn make_unique(node: &mut Rc<RefCell<TreeNode>>) {
+    if Rc::strong_count(node) > 1 {
+        let copy = node.borrow().shallow_clone();
+        *node = Rc::new(RefCell::new(copy));
+    }
+}
+
+// Replaces the count under `hash` with `update(old count)`, 0 meaning absent, in a single walk
+// down its path. Nothing is marked dirty when `update` refuses. A removal replaces an inner
+// node left with a single leaf under it by that leaf, so the shape is the one the tree
+// would have without the key. With full-depth leaves new leaves are chained down to depth
+// `HASH_LENGTH` instead, and a removal drops the inner nodes left without children.
+pub(super) fn update_leaf<F: FnOnce(u64) -> Option<u64>>(
+    slot: &mut Option<Rc<RefCell<TreeNode>>>,
+    hash: &HashBytes,
+    update: F,
+    commitment: &LeafCommitment,
+    empty: &EmptyHashes,
+) -> Result<u64, CountOverflow> {
+    // Inner nodes from the root down, the node at level i in path[i], then the leaf or
+    // empty slot below the last of them
+    let mut path: Vec<Rc<RefCell<TreeNode>>> = vec![];
+    let mut terminal = slot.as_mut().map(|root| {
+        make_unique(root);
+        Rc::clone(root)
+    });
+    while let Some(node) = terminal.clone().filter(|node| !node.borrow().is_leaf()) {
+        let level = path.len();
+        terminal = {
+            let mut inner = node.borrow_mut();
+            let inner = &mut *inner;
+            next_child(&mut inner.left, &mut inner.right, hash, level).0.as_mut().map(|child| {
+                make_unique(child);
+                Rc::clone(child)
+            })
+        };
+        path.push(node);
+    }
+
+    let old = match terminal {
+        Some(ref leaf) if leaf.borrow().key_hash_raw() == hash.bytes() => leaf.borrow().get_version().unwrap(),
+        _ => 0,
+    };
+    let count = update(old).ok_or(CountOverflow)?;
+    if count == old {
+        return Ok(count);
+    }
+    match terminal {
+        Some(leaf) if count > 0 && old > 0 => leaf.borrow_mut().update_version(count, commitment),
+        Some(leaf) if count > 0 => leaf.borrow_mut().split_leaf(hash, path.len(), count, commitment),
+        // A new leaf in an empty slot, or no leaf in place of the removed one
+        _ => {
+            let leaf = if count > 0 {
+                let leaf = Rc::new(RefCell::new(TreeNode::new_committed_leaf(hash.clone(), count, commitment)));
+                if empty.full_depth() {
+                    let below: Vec<u8> = (path.len()..HASH_LENGTH).map(|level| hash.bit(level)).collect();
+                    Some(chain_to(leaf, &below))
+                } else {
+                    Some(leaf)
+                }
+            } else {
+                None
+            };
+            match path.last() {
+                Some(parent) => {
+                    let mut parent = parent.borrow_mut();
+                    let parent = &mut *parent;
+                    *next_child(&mut parent.left, &mut parent.right, hash, path.len() - 1).0 = leaf;
+                }
+                None => *slot = leaf,
+            }
+        }
+    }
+    if count > 0 {
+        for node in &path {
+            node.borrow_mut().mark_dirty();
+        }
+        return Ok(count);
+    }
+    if empty.full_depth() {
+        // Back up the path, dropping nodes left without children
+        let mut emptied = false;
+        for (level, node) in path.iter().enumerate().rev() {
+            let mut node = node.borrow_mut();
+            let node = &mut *node;
+            if emptied {
+                *next_child(&mut node.left, &mut node.right, hash, level).0 = None;
+            }
+            emptied = node.left.is_none() && node.right.is_none();
+            node.mark_dirty();
+        }
+        if emptied {
+            *slot = None;
+        }
+        return Ok(count);
+    }
+    // Back up the path, lifting lone leaves into the slot of their parent
+    let mut lifted = None;
+    for (level, node) in path.iter().enumerate().rev() {
+        let mut node = node.borrow_mut();
+        let node = &mut *node;
+        if let Some(leaf) = lifted.take() {
+            *next_child(&mut node.left, &mut node.right, hash, level).0 = Some(leaf);
+        }
+        match (&node.left, &node.right) {
+            (Some(child), None) | (None, Some(child)) if child.borrow().is_leaf() => lifted = Some(Rc::clone(child)),
+            _ => node.mark_dirty(),
+        }
+    }
+    if lifted.is_some() {
+        *slot = lifted;
+    }
+    Ok(count)
+}
+
+// Next move of a lookup
+enum Lookup {
+    Descend(Rc<RefCell<TreeNode>>),
+    Done(Option<u64>),
+}
+
+// Walks down the bit path `prefix` and returns the node standing for that subtree.
+// A leaf met above the prefix depth stands for the subtree only if its own path continues the prefix.
+pub(super) fn descend(root: &Rc<RefCell<TreeNode>>, prefix: &[u8]) -> Option<Rc<RefCell<TreeNode>>> {
+    let mut node = Rc::clone(root);
+    for level in 0..prefix.len() {
+        let next = {
+            let current = node.borrow();
+            if let NodeKind::Leaf(ref hashbytes) = current.entry {
+                let on_path = (level..prefix.len()).all(|i| hashbytes.bit(i) == prefix[i]);
+                return if on_path { Some(Rc::clone(&node)) } else { None };
+            }
+            let child = if prefix[level] == 0 { &current.left } else { &current.right };
+            match child {
+                Some(child) => Rc::clone(child),
+                None => return None,
+            }
+        };
+        node = next;
+    }
+    Some(node)
+}
+
+// Recomputes the hashes of the dirty nodes below `root`, children first. The ancestors of a
+// dirty node are always dirty, so clean subtrees are skipped whole. Nodes wait on an explicit
+// stack instead of the call stack, and each is borrowed only while it is looked at.
+pub(super) fn rehash(root: &Rc<RefCell<TreeNode>>, empty: &EmptyHashes, nodes: &NodeCommitment) {
+    // (node, level, whether its children were already pushed)
+    let mut stack = vec![(Rc::clone(root), 0, false)];
+    while let Some((node, level, expanded)) = stack.pop() {
+        if expanded {
+            node.borrow_mut().refresh(level, empty, nodes);
+            continue;
+        }
+        let children: Vec<_> = {
+            let current = node.borrow();
+            if !current.dirty {
+                continue;
+            }
+            [&current.left, &current.right].iter().copied().flatten().map(Rc::clone).collect()
+        };
+        stack.push((node, level, true));
+        stack.extend(children.into_iter().map(|child| (child, level + 1, false)));
+    }
+}
+
+// Hangs `node` below a chain of single-child inner nodes following `prefix`, left dirty
+// so that the next `rehash` computes their hashes.
+pub(super) fn chain_to(node: Rc<RefCell<TreeNode>>, prefix: &[u8]) -> Rc<RefCell<TreeNode>> {
+    let mut node = node;
+    for &bit in prefix.iter().rev() {
+        let mut parent = TreeNode::new();
+        if bit == 0 {
+            parent.left = Some(node);
+        } else {
+            parent.right = Some(node);
+        }
+        parent.mark_dirty();
+        node = Rc::new(RefCell::new(parent));
+    }
+    node
+}
+
+// Sibling subtrees along the path of the hash, root first, `None` for empty slots.
+// They match the siblings of `get_proof_hash` one for one.
+pub(super) fn path_siblings(root: &Rc<RefCell<TreeNode>>, hash: &HashBytes, empty: &EmptyHashes) -> Vec<Option<Rc<RefCell<TreeNode>>>> {
+    let mut siblings = vec![];
+    let mut node = Rc::clone(root);
+    for level in 0..HASH_LENGTH {
+        let next = {
+            let current = node.borrow();
+            if current.is_leaf() {
+                break;
+            }
+            let (next, alt) = next_child(&current.left, &current.right, hash, level);
+            siblings.push(alt.clone());
+            match next {
+                Some(next) => Rc::clone(next),
+                None => break,
+            }
+        };
+        node = next;
+    }
+    if empty.full_depth() {
+        siblings.resize(HASH_LENGTH, None);
+    }
+    siblings
+}
+
+// Leaf found by walking down with `target` below the weight of the subtree, skipping the
+// weight of every left child passed over
+pub(super) fn leaf_at_weight<W: Fn(&TreeNode) -> u128>(root: &Rc<RefCell<TreeNode>>, target: u128, weight: W) -> Rc<RefCell<TreeNode>> {
+    let mut target = target;
+    let mut node = Rc::clone(root);
+    loop {
+        let next = {
+            let current = node.borrow();
+            if current.is_leaf() {
+                break;
+            }
+            let left = current.left.as_ref().map_or(0, |left| weight(&left.borrow()));
+            if target < left {
+                Rc::clone(current.left.as_ref().unwrap())
+            } else {
+                target -= left;
+                Rc::clone(current.right.as_ref().unwrap())
+            }
+        };
+        node = next;
+    }
+    node
 }
 
 fn next_child<T>(left: T, right: T, hash: &HashBytes, level: usize) -> (T, T) {
@@ -197,12 +530,16 @@ fn next_child<T>(left: T, right: T, hash: &HashBytes, level: usize) -> (T, T) {
     }
 }
 
-pub fn hash_from_version(version: i32, key_hash: &HashBytes) -> HashBytes {
-    // Transform i32 to vector of bytes.
+// Counts that fit the original 4-byte field keep its encoding, so roots computed before
+// the counter was widened still verify. Wider counts are tagged with `WIDE_LEAF_ENCODING`,
+// which also makes their preimage a different length from every other hashed node.
+pub fn hash_from_version(version: u64, key_hash: &HashBytes) -> HashBytes {
     let mut bytes: Vec<u8> = vec![];
-    let mask = (1 << 8) - 1;
-    for i in 0..4 {
-        bytes.push((version >> (i * 8) & mask) as u8);
+    if version <= LEGACY_MAX_COUNT {
+        bytes.extend(&(version as u32).to_le_bytes());
+    } else {
+        bytes.push(WIDE_LEAF_ENCODING);
+        bytes.extend(&version.to_le_bytes());
     }
     //Attach bytes to key_hash and hash
     bytes.extend(key_hash.bytes());
@@ -210,35 +547,64 @@ pub fn hash_from_version(version: i32, key_hash: &HashBytes) -> HashBytes {
 }
 
 //TODO replace digest with Hashable trait functions
-fn hash_from_children(
+pub(crate) fn hash_from_children(
     node1: &Option<Rc<RefCell<TreeNode>>>,
     node2: &Option<Rc<RefCell<TreeNode>>>,
+    level: usize,
+    empty: &EmptyHashes,
+    nodes: &NodeCommitment,
 ) -> HashBytes {
-    let mut to_hash: Vec<u8>;
-    match node1 {
+    let child = |node: &Option<Rc<RefCell<TreeNode>>>| match node {
         Some(node) => {
-            let temp = node.borrow();
-            to_hash = temp.version_hash_raw().clone();
-        }
-        None => {
-            to_hash = DEFAULT_HASH.to_vec();
+            let node = node.borrow();
+            (node.version_hash_raw().clone(), node.total(), node.leaf_count())
         }
-    }
-    match node2 {
-        Some(node) => {
-            to_hash.extend(node.borrow().version_hash_raw());
-        }
-        None => {
-            to_hash.extend(DEFAULT_HASH);
+        None => (empty.at_depth(level + 1).to_vec(), 0, 0),
+    };
+    let (left, left_total, left_leaves) = child(node1);
+    let (right, right_total, right_leaves) = child(node2);
+    HashBytes::new(node_hash(nodes, (&left, left_leaves, left_total), (&right, right_leaves, right_total)))
+}
+
+// Inner hash over the (hash, leaves, total) of each child under the given node commitment
+pub(crate) fn node_hash(nodes: &NodeCommitment, left: (&[u8], u64, u128), right: (&[u8], u64, u128)) -> Digest {
+    let ((left, left_leaves, left_total), (right, right_leaves, right_total)) = (left, right);
+    match nodes {
+        NodeCommitment::Hash => {
+            let mut to_hash = left.to_vec();
+            to_hash.extend(right);
+            to_hash.raw_hash()
         }
+        NodeCommitment::Sum => sum_node_hash(left, left_total, right, right_total),
+        NodeCommitment::Counted => counted_node_hash(left, (left_leaves, left_total), right, (right_leaves, right_total)),
     }
-    to_hash.hash()
 }
-#[derive(Debug)]
+
+// Inner hash under `NodeCommitment::Sum`. Its preimage is longer than that of any other node.
+pub(crate) fn sum_node_hash(left: &[u8], left_total: u128, right: &[u8], right_total: u128) -> Digest {
+    let mut to_hash = left.to_vec();
+    to_hash.extend(&left_total.to_le_bytes());
+    to_hash.extend(right);
+    to_hash.extend(&right_total.to_le_bytes());
+    to_hash.raw_hash()
+}
+
+// Inner hash under `NodeCommitment::Counted`, each child followed by its number of leaves
+// and total count
+pub(crate) fn counted_node_hash(left: &[u8], left_counts: (u64, u128), right: &[u8], right_counts: (u64, u128)) -> Digest {
+    let mut to_hash = left.to_vec();
+    to_hash.extend(&left_counts.0.to_le_bytes());
+    to_hash.extend(&left_counts.1.to_le_bytes());
+    to_hash.extend(right);
+    to_hash.extend(&right_counts.0.to_le_bytes());
+    to_hash.extend(&right_counts.1.to_le_bytes());
+    to_hash.raw_hash()
+}
+#[derive(Debug, Clone, PartialEq)]
 pub enum ProofNode {
     Left(Vec<u8>),
     Right(Vec<u8>),
-    Leaf(Vec<u8>, i32),
+    Leaf(Vec<u8>, u64),
     None,
 }
 
@@ -258,13 +624,14 @@ impl ProofNode {
         to_hash.hash().bytes().clone()
     }
 
-    fn hash(self) -> Vec<u8>{
+    // Hash of a terminal node found `depth` levels below the root
+    fn hash(self, depth: usize, empty: &EmptyHashes) -> Vec<u8>{
         let to_hash = match self {
             Self::Leaf(key_hash,version) => {
                 hash_from_version(version, &HashBytes::new(key_hash)).bytes().clone()
             },
             Self::None => {
-                DEFAULT_HASH.to_vec()
+                empty.at_depth(depth).to_vec()
             },
             _ => panic!("hashing proofnode"),
         };
@@ -273,10 +640,68 @@ impl ProofNode {
 }
 
 pub fn roothash_from_proof(proof: &mut Vec<ProofNode>) -> Vec<u8> {
-    let mut ans = proof.pop().unwrap().hash();
-    while let Some(node) = proof.pop() {
+    roothash_from_proof_with(proof, &EmptyHashes::Constant)
+}
+
+/// `roothash_from_proof` for a tree built with the given empty hashes.
+pub fn roothash_from_proof_with(proof: &mut Vec<ProofNode>, empty: &EmptyHashes) -> Vec<u8> {
+    let terminal = proof.pop().unwrap();
+    let ans = terminal.hash(proof.len(), empty);
+    roothash_from_siblings(proof, ans)
+}
+
+// Folds the siblings, deepest last, over an already computed leaf hash
+pub(crate) fn roothash_from_siblings(siblings: &mut Vec<ProofNode>, leaf_hash: Vec<u8>) -> Vec<u8> {
+    let mut ans = leaf_hash;
+    while let Some(node) = siblings.pop() {
         ans = node.add_and_hash(&mut ans);
         //println!("ans {:?}", ans);
     }
     ans
-}
\ No newline at end of file
+}
+
+// Extends the siblings of the path of `hash` with empty subtrees down to depth `HASH_LENGTH`
+pub(crate) fn push_empty_siblings(siblings: &mut Vec<ProofNode>, hash: &HashBytes, empty: &EmptyHashes) {
+    for level in siblings.len()..HASH_LENGTH {
+        let sibling = empty.at_depth(level + 1).to_vec();
+        siblings.push(if hash.bit(level) == 1 { ProofNode::Left(sibling) } else { ProofNode::Right(sibling) });
+    }
+}
+
+// Siblings have to follow the key's own bit path
+pub(crate) fn follows_key_path(siblings: &[ProofNode], key_hash: &HashBytes) -> bool {
+    siblings.len() <= HASH_LENGTH
+        && siblings.iter().enumerate().all(|(level, node)| match node {
+        ProofNode::Left(_) => key_hash.bit(level) == 1,
+        ProofNode::Right(_) => key_hash.bit(level) == 0,
+        _ => false,
+    })
+}
+
+// Count the proof shows for the key under `root`, 0 meaning absent. None if the proof is invalid.
+pub fn verify_proof<T: Hashable>(root: &[u8], key: T, proof: &[ProofNode]) -> Option<u64> {
+    verify_proof_with(root, key, proof, &EmptyHashes::Constant)
+}
+
+/// `verify_proof` for a tree built with the given empty hashes.
+pub fn verify_proof_with<T: Hashable>(root: &[u8], key: T, proof: &[ProofNode], empty: &EmptyHashes) -> Option<u64> {
+    let key_hash = key.hash();
+    match proof.split_last()? {
+        (ProofNode::Leaf(leaf_hash, count), siblings) if leaf_hash == key_hash.bytes() => {
+            let valid = follows_key_path(siblings, &key_hash) && roothash_from_proof_with(&mut proof.to_vec(), empty) == root;
+            if valid {
+                Some(*count)
+            } else {
+                None
+            }
+        }
+        _ => {
+            let absence = NonMembershipProof::from_proof(proof.to_vec())?;
+            if absence.verify_hash(root, &key_hash, empty) {
+                Some(0)
+            } else {
+                None
+            }
+        }
+    }
+}
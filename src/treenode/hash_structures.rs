@@ -1,4 +1,5 @@
 use crypto_hash::{digest, Algorithm};
+use std::sync::OnceLock;
 
 pub trait Hashable {
     fn hash(&self) -> HashBytes;
@@ -27,6 +28,37 @@ impl Hashable for usize {
     }
 }
 
+impl Hashable for &str {
+    fn hash(&self) -> HashBytes {
+        HashBytes::new(digest(Algorithm::SHA256, self.as_bytes()))
+    }
+    fn raw_hash(&self) -> Vec<u8> {
+        digest(Algorithm::SHA256, self.as_bytes())
+    }
+}
+
+impl Hashable for String {
+    fn hash(&self) -> HashBytes {
+        self.as_str().hash()
+    }
+    fn raw_hash(&self) -> Vec<u8> {
+        self.as_str().raw_hash()
+    }
+}
+
+/// Key whose hash was computed beforehand, e.g. under a keyed tree.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Prehashed(pub Vec<u8>);
+
+impl Hashable for Prehashed {
+    fn hash(&self) -> HashBytes {
+        HashBytes::new(self.0.clone())
+    }
+    fn raw_hash(&self) -> Vec<u8> {
+        self.0.clone()
+    }
+}
+
 impl Hashable for Vec<u8> {
     fn hash(&self) -> HashBytes {
         HashBytes::new(digest(Algorithm::SHA256, self))
@@ -37,6 +69,7 @@ impl Hashable for Vec<u8> {
 }
 
 
+#[derive(Clone)]
 pub(super) enum NodeKind {
     Leaf(HashBytes),
     Inner(HashBytes),
@@ -45,10 +78,7 @@ pub(super) enum NodeKind {
 
 impl NodeKind {
     pub(super) fn is_leaf(&self) -> bool {
-        match self {
-            NodeKind::Leaf(_) => true,
-            _ => false,
-        }
+        matches!(self, NodeKind::Leaf(_))
     }
 
     pub(super) fn get_hash(&self) -> &HashBytes {
@@ -60,22 +90,155 @@ impl NodeKind {
     }
 }
 
+/// How a leaf hash commits to the count of its key.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum LeafCommitment {
+    /// `H(count || key_hash)`; proofs reveal the count.
+    #[default]
+    Count,
+    /// `H(CHAIN_TAG || key_hash || H^count(seed))`, the seed being `HMAC-SHA256(secret, key_hash)`.
+    /// Proofs reveal a lower bound on the count only, see `threshold`; the other proofs
+    /// cannot show these leaves. Counts are limited to `MAX_CHAIN_COUNT`.
+    HashChain(Vec<u8>),
+}
+
+/// Largest count of a hash-chain leaf, which takes one hash per unit of count to build.
+pub const MAX_CHAIN_COUNT: u64 = 1 << 20;
+
+impl LeafCommitment {
+    /// Largest count a leaf can hold under this commitment.
+    pub fn max_count(&self) -> u64 {
+        match self {
+            LeafCommitment::Count => u64::MAX,
+            LeafCommitment::HashChain(_) => MAX_CHAIN_COUNT,
+        }
+    }
+}
+
+/// Hash standing for an empty subtree.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum EmptyHashes {
+    /// `[1; 32]` at every depth.
+    #[default]
+    Constant,
+    /// Zeroes at height 0 and `H(empty_{h-1} || empty_{h-1})` at height h, the root being at
+    /// height 256. Leaves sit at depth 256 below a node per bit of their key hash, so roots
+    /// and proofs are those of a standard 256-level sparse Merkle tree.
+    PerLevel,
+}
+
+/// What an inner node hash commits to.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum NodeCommitment {
+    /// `H(left || right)`.
+    #[default]
+    Hash,
+    /// `H(left || le16(left_total) || right || le16(right_total))`, totals being the sums of
+    /// the counts below each child, so the root commits to the total count.
+    Sum,
+    /// `H(left || le8(left_leaves) || le16(left_total) || right || le8(right_leaves) || le16(right_total))`,
+    /// also committing to the number of keys below each child for rank proofs.
+    Counted,
+}
+
+impl EmptyHashes {
+    /// Hash of an empty subtree whose root sits `depth` levels below the tree root.
+    pub fn at_depth(&self, depth: usize) -> &'static [u8] {
+        match self {
+            EmptyHashes::Constant => super::DEFAULT_HASH,
+            EmptyHashes::PerLevel => &per_level_table()[super::HASH_LENGTH.saturating_sub(depth)],
+        }
+    }
+
+    // Leaves sit at depth `HASH_LENGTH` instead of the shallowest level that sets them apart
+    pub(crate) fn full_depth(&self) -> bool {
+        *self == EmptyHashes::PerLevel
+    }
+}
+
+// Empty hashes indexed by height, computed once
+fn per_level_table() -> &'static [Vec<u8>] {
+    static TABLE: OnceLock<Vec<Vec<u8>>> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        let mut table = vec![vec![0; 32]];
+        for height in 1..=super::HASH_LENGTH {
+            let mut bytes = table[height - 1].clone();
+            bytes.extend(&table[height - 1]);
+            table.push(bytes.raw_hash());
+        }
+        table
+    })
+}
+
+pub(crate) const CHAIN_TAG: &[u8] = b"chain";
+
+// Start of the chain. Being an HMAC output, its only known preimage is longer than a link.
+pub(crate) fn chain_seed(secret: &[u8], key_hash: &HashBytes) -> Vec<u8> {
+    hmac_sha256(secret, key_hash.bytes())
+}
+
+pub(crate) fn hash_chain(start: &[u8], steps: u64) -> Vec<u8> {
+    let mut link = start.to_vec();
+    for _ in 0..steps {
+        link = link.raw_hash();
+    }
+    link
+}
+
+pub(crate) fn chain_leaf_hash(key_hash: &[u8], link: &[u8]) -> HashBytes {
+    let mut bytes = CHAIN_TAG.to_vec();
+    bytes.extend(key_hash);
+    bytes.extend(link);
+    bytes.hash()
+}
+
+#[derive(Clone)]
 pub(super) struct Version {
-    pub(super) value: i32,
+    pub(super) value: u64,
     pub(super) hash: HashBytes,
+    /// `H^value(seed)` under `LeafCommitment::HashChain`.
+    pub(super) chain: Option<Vec<u8>>,
 }
 
 impl Version {
-    pub(super) fn new(value: i32, key_hash: &HashBytes) -> Self{
-        Self {
+    pub(super) fn new(value: u64, key_hash: &HashBytes) -> Self{
+        Self::committed(value, key_hash, &LeafCommitment::Count)
+    }
+
+    pub(super) fn committed(value: u64, key_hash: &HashBytes, commitment: &LeafCommitment) -> Self {
+        let mut version = Self {
             value,
             hash: super::hash_from_version(value, key_hash),
+            chain: None,
+        };
+        if let LeafCommitment::HashChain(ref secret) = commitment {
+            version.advance_chain(hash_chain(&chain_seed(secret, key_hash), value), value, key_hash);
         }
+        version
     }
 
+    pub(super) fn set(&mut self, value: u64, key_hash: &HashBytes, commitment: &LeafCommitment) {
+        match commitment {
+            LeafCommitment::Count => *self = Self::new(value, key_hash),
+            LeafCommitment::HashChain(ref secret) => {
+                // Growing counts extend the current link instead of hashing from the seed again
+                let link = match self.chain {
+                    Some(ref link) if value >= self.value => hash_chain(link, value - self.value),
+                    _ => hash_chain(&chain_seed(secret, key_hash), value),
+                };
+                self.advance_chain(link, value, key_hash);
+            }
+        }
+    }
+
+    fn advance_chain(&mut self, link: Vec<u8>, value: u64, key_hash: &HashBytes) {
+        self.value = value;
+        self.hash = chain_leaf_hash(key_hash.bytes(), &link);
+        self.chain = Some(link);
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct HashBytes {
     bytes: Vec<u8>,
 }
@@ -90,8 +253,35 @@ impl HashBytes {
         &self.bytes
     }
 
-    pub(super) fn bit(&self, index: usize) -> u8 {
+    pub(crate) fn bit(&self, index: usize) -> u8 {
         let (byte_index, bit_index) = (index >> 3, index & 7);
         (self.bytes[byte_index] >> (7 - bit_index)) & 1
     }
 }
+
+const HMAC_BLOCK_SIZE: usize = 64;
+
+/// HMAC-SHA256 as specified in RFC 2104.
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut block = if key.len() > HMAC_BLOCK_SIZE { digest(Algorithm::SHA256, key) } else { key.to_vec() };
+    block.resize(HMAC_BLOCK_SIZE, 0);
+    let mut inner: Vec<u8> = block.iter().map(|byte| byte ^ 0x36).collect();
+    inner.extend(message);
+    let mut outer: Vec<u8> = block.iter().map(|byte| byte ^ 0x5c).collect();
+    outer.extend(digest(Algorithm::SHA256, &inner));
+    digest(Algorithm::SHA256, &outer)
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
@@ -21,7 +21,7 @@ fn insert_contains() {
     //check
     for i in 0..v.len() {
         if v[i]>0 {
-            assert_eq!(testree.contains(i).unwrap(), v[i]);
+            assert_eq!(testree.contains(i).unwrap(), v[i] as u64);
         } else {
             assert!(testree.contains(i) == None);
         }
@@ -45,7 +45,7 @@ fn proof_contains() {
     //check
     for i in 0..v.len() {
         if v[i]>0 {
-            assert_eq!(testree.contains(i).unwrap(), v[i]);
+            assert_eq!(testree.contains(i).unwrap(), v[i] as u64);
         } else {
             assert!(testree.contains(i) == None);
         }
@@ -112,6 +112,1143 @@ fn multiple_insert_variance() {
 }
 
 
+#[test]
+fn sync_diverged_replicas() {
+    let (mut replica1, mut replica2) = (MerkleTree::new(), MerkleTree::new());
+    let v = rand_int_vec(3u64, 300, 5);
+    for (i, &count) in v.iter().enumerate() {
+        for _ in 0..count {
+            replica1.insert(i);
+            replica2.insert(i);
+        }
+    }
+    // Diverge by a few keys on each side
+    replica1.insert(1000usize);
+    replica1.insert(7usize);
+    replica2.insert(2000usize);
+    replica2.insert(2000usize);
+    for _ in 0..3 {
+        replica2.insert(11usize);
+    }
+    assert_ne!(replica1.get_hash(), replica2.get_hash());
+
+    let messages = sync::sync_loopback(&mut replica1, &mut replica2).unwrap();
+    assert_eq!(replica1.get_hash(), replica2.get_hash());
+    // One request and one reply per level, plus the final leaf exchange
+    assert!(messages < 64);
+    for tree in [&mut replica1, &mut replica2].iter_mut() {
+        assert_eq!(tree.contains(1000usize), Some(1));
+        assert_eq!(tree.contains(2000usize), Some(2));
+        assert_eq!(tree.contains(7usize), Some(std::cmp::max(v[7], 0) as u64 + 1));
+        assert_eq!(tree.contains(11usize), Some(std::cmp::max(v[11], 0) as u64 + 3));
+    }
+
+    // Already converged replicas only compare roots
+    assert_eq!(sync::sync_loopback(&mut replica1, &mut replica2), Ok(3));
+}
+
+#[test]
+fn sync_with_empty_replica() {
+    let (mut full, mut empty) = (MerkleTree::new(), MerkleTree::new());
+    for i in 0..50usize {
+        full.insert(i);
+    }
+    sync::sync_loopback(&mut empty, &mut full).unwrap();
+    assert_eq!(full.get_hash(), empty.get_hash());
+}
+
+#[test]
+fn sync_rejects_protocol_violations() {
+    use sync::{Summary, SyncError, SyncMessage, SyncSession};
+
+    let mut tree = MerkleTree::new();
+    tree.insert(1usize);
+    tree.insert(2usize);
+
+    let mut session = SyncSession::new();
+    assert_eq!(session.handle(&mut tree, SyncMessage::Leaves(vec![])), Err(SyncError::UnexpectedMessage));
+    assert!(session.is_finished());
+
+    let mut session = SyncSession::new();
+    session.start();
+    let reply = SyncMessage::Summaries(vec![(vec![], Summary::Empty), (vec![0], Summary::Empty)]);
+    assert_eq!(session.handle(&mut tree, reply), Err(SyncError::SummaryMismatch));
+
+    let mut session = SyncSession::new();
+    let push = SyncMessage::Reconcile { push: vec![(vec![1, 2, 3], 5)], pull: vec![] };
+    assert_eq!(session.handle(&mut tree, push), Err(SyncError::Malformed));
+    let prefixes = SyncMessage::GetSummaries(vec![vec![2]]);
+    assert_eq!(SyncSession::new().handle(&mut tree, prefixes), Err(SyncError::Malformed));
+    assert_eq!(tree.len(), 2);
+}
+
+
+#[test]
+fn update_proofs() {
+    let mut testree = MerkleTree::new();
+    let v = rand_int_vec(4u64, 200, 10);
+
+    for &value in v.iter() {
+        let old_root = testree.get_hash();
+        let old_count = testree.contains(value).unwrap_or(0);
+        let proof = testree.insert_with_proof(value);
+        let new_root = testree.get_hash().unwrap();
+        assert!(update_proof::verify_update(old_root.as_deref(), &new_root, value, old_count, old_count + 1, &proof));
+        // Wrong counts or a different key must not verify
+        assert!(!update_proof::verify_update(old_root.as_deref(), &new_root, value, old_count, old_count + 2, &proof));
+        assert!(!update_proof::verify_update(old_root.as_deref(), &new_root, value + 100, 0, 1, &proof));
+    }
+
+    // Untrusted proofs ending in a sibling are rejected, not hashed
+    let root = testree.get_hash().unwrap();
+    let forged = update_proof::UpdateProof { path: vec![ProofNode::Left(root.clone())] };
+    assert!(!update_proof::verify_update(Some(&root), &root, 1, 0, 1, &forged));
+
+    let mut testree = MerkleTree::with_empty_hashes(EmptyHashes::PerLevel);
+    let empty = EmptyHashes::PerLevel;
+    for &value in v.iter() {
+        let old_root = testree.get_hash();
+        let old_count = testree.get_count(value);
+        let proof = testree.insert_with_proof(value);
+        let new_root = testree.get_hash().unwrap();
+        assert!(update_proof::verify_update_with(old_root.as_deref(), &new_root, value, old_count, old_count + 1, &proof, &empty));
+    }
+}
+
+#[test]
+fn batch_update_proof() {
+    let mut testree = MerkleTree::new();
+    for i in 0..20 {
+        testree.insert(i);
+    }
+    let keys = vec![3, 50, 50, 7, 400];
+    let mut expected = vec![];
+    let mut counts = std::collections::HashMap::new();
+    for &key in keys.iter() {
+        let old = *counts.entry(key).or_insert_with(|| testree.contains(key).unwrap_or(0));
+        counts.insert(key, old + 1);
+        expected.push((key, old, old + 1));
+    }
+
+    let old_root = testree.get_hash();
+    let proof = testree.insert_batch_with_proof(keys);
+    let new_root = testree.get_hash().unwrap();
+    assert!(update_proof::verify_batch_update(old_root.as_deref(), &new_root, expected.clone(), &proof));
+    expected.swap(0, 1);
+    assert!(!update_proof::verify_batch_update(old_root.as_deref(), &new_root, expected, &proof));
+}
+
+
+#[test]
+fn root_log_consistency() {
+    let mut testree = MerkleTree::new();
+    let mut log = root_log::RootLog::new();
+    log.record_at(&testree, root_log::Operation::Sync, 0);
+    for i in 0..20usize {
+        testree.insert(i % 7);
+        log.record_at(&testree, root_log::Operation::Insert((i % 7).raw_hash()), i as u64);
+    }
+    assert_eq!(log.entries().last().unwrap().root, testree.get_hash());
+
+    for new_size in 1..=log.len() {
+        let new_root = log.log_root(new_size);
+        for old_size in 1..=new_size {
+            let proof = log.consistency_proof(old_size, new_size);
+            let old_root = log.log_root(old_size);
+            assert!(root_log::verify_consistency(old_size, new_size, &old_root, &new_root, &proof));
+            if old_size < new_size {
+                assert!(!root_log::verify_consistency(old_size, new_size, &new_root, &new_root, &proof));
+            }
+        }
+        for index in 0..new_size {
+            let proof = log.inclusion_proof(index, new_size);
+            let entry = &log.entries()[index];
+            assert!(root_log::verify_inclusion(index, new_size, entry, &new_root, &proof));
+            let mut forged = entry.clone();
+            forged.timestamp += 1;
+            assert!(!root_log::verify_inclusion(index, new_size, &forged, &new_root, &proof));
+        }
+    }
+}
+
+
+#[test]
+fn signed_checkpoints() {
+    let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(9);
+    let key = ed25519_dalek::SigningKey::generate(&mut rng);
+    let other_key = ed25519_dalek::SigningKey::generate(&mut rng);
+    let mut testree = MerkleTree::new();
+    assert_eq!(
+        checkpoint::Checkpoint::from_tree("audit", 0, &testree),
+        Err(checkpoint::CheckpointError::EmptyTree)
+    );
+    for i in 0..30 {
+        testree.insert(i);
+    }
+
+    let signed = checkpoint::Checkpoint::from_tree("audit", 7, &testree).unwrap().sign(&key);
+    assert_eq!(signed.checkpoint.size, 30);
+    assert!(signed.verify(&key.verifying_key()));
+    assert!(!signed.verify(&other_key.verifying_key()));
+    assert_eq!(checkpoint::SignedCheckpoint::from_text(&signed.to_text()).unwrap(), signed);
+    assert_eq!(checkpoint::SignedCheckpoint::from_bytes(&signed.to_bytes().unwrap()).unwrap(), signed);
+    assert!(checkpoint::SignedCheckpoint::from_bytes(&signed.to_bytes().unwrap()[1..]).is_err());
+    let mut oversized = signed.clone();
+    oversized.checkpoint.root_hash = vec![0; 256];
+    assert!(oversized.to_bytes().is_err());
+    assert_eq!(
+        checkpoint::Checkpoint::from_tree("audit\n8", 7, &testree),
+        Err(checkpoint::CheckpointError::Malformed("tree id"))
+    );
+
+    let public_key = key.verifying_key();
+    assert_eq!(signed.verify_proof(&public_key, 5, &testree.get_proof(5)), Some(1));
+    assert_eq!(signed.verify_proof(&public_key, 6, &testree.get_proof(5)), None);
+    assert_eq!(signed.verify_proof(&public_key, 100, &testree.get_proof(100)), Some(0));
+    let mut tampered = signed.clone();
+    tampered.checkpoint.sequence += 1;
+    assert_eq!(tampered.verify_proof(&public_key, 5, &testree.get_proof(5)), None);
+    let mut truncated = testree.get_proof(5);
+    truncated.pop();
+    assert_eq!(signed.verify_proof(&public_key, 5, &truncated), None);
+    testree.insert(5);
+    assert_eq!(signed.verify_proof(&public_key, 5, &testree.get_proof(5)), None);
+}
+
+
+#[test]
+fn transaction_commit_and_rollback() {
+    let (mut testree, mut expected) = (MerkleTree::new(), MerkleTree::new());
+    for i in 0..40 {
+        testree.insert(i);
+        expected.insert(i);
+    }
+    let original_root = testree.get_hash();
+    let original = testree.share();
+
+    let mut transaction = testree.transaction();
+    for i in 35..60 {
+        transaction.insert(i);
+        expected.insert(i);
+    }
+    assert_eq!(transaction.contains(36), Some(2));
+    assert_eq!(transaction.contains(50), Some(1));
+    assert_eq!(transaction.get_hash(), expected.get_hash());
+    transaction.rollback();
+    assert_eq!(testree.get_hash(), original_root);
+    assert!(Rc::ptr_eq(testree.root.as_ref().unwrap(), original.root.as_ref().unwrap()));
+    assert_eq!(testree.contains(50), None);
+
+    let mut transaction = testree.transaction();
+    for i in 35..60 {
+        transaction.insert(i);
+    }
+    let tentative = transaction.get_hash();
+    transaction.commit();
+    assert_eq!(testree.get_hash(), tentative);
+    assert_eq!(testree.get_hash(), expected.get_hash());
+    // The snapshot taken before the transaction kept its own nodes
+    assert_eq!(original.get_hash(), original_root);
+    assert_eq!(original.root.as_ref().unwrap().borrow().contains(&50.hash()), None);
+
+    // A logged commit records the committed root
+    let mut log = root_log::RootLog::new();
+    let mut transaction = testree.transaction();
+    transaction.insert(100);
+    transaction.insert(101);
+    assert_eq!(transaction.commit_logged(&mut log), 0);
+    assert_eq!(log.entries()[0].operation, root_log::Operation::Batch(2));
+    assert_eq!(log.entries()[0].root, testree.get_hash());
+}
+
+
+#[test]
+fn wal_recovers_consistent_prefix() {
+    let directory = std::env::temp_dir().join(format!("merkle_wal_{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let path = directory.join("tree.wal");
+    let _ = std::fs::remove_file(&path);
+
+    let v = rand_int_vec(6u64, 60, 15);
+    let mut prefix_roots = vec![None];
+    let mut expected = MerkleTree::new();
+    {
+        let mut durable = wal::DurableTree::open(&path).unwrap();
+        for &value in v.iter() {
+            durable.insert(value).unwrap();
+            expected.insert(value);
+            prefix_roots.push(expected.get_hash());
+        }
+        assert_eq!(durable.get_hash(), expected.get_hash());
+    }
+    let reopened = wal::DurableTree::open(&path).unwrap();
+    assert_eq!(reopened.records(), v.len());
+    assert_eq!(reopened.get_hash(), expected.get_hash());
+    drop(reopened);
+
+    // Every truncation point recovers exactly the records that are still complete
+    let log = std::fs::read(&path).unwrap();
+    let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(6);
+    let truncated = directory.join("truncated.wal");
+    for _ in 0..40 {
+        let cut = rng.gen_range(0..log.len());
+        std::fs::write(&truncated, &log[..cut]).unwrap();
+        let mut recovered = wal::DurableTree::open(&truncated).unwrap();
+        assert_eq!(recovered.get_hash(), prefix_roots[recovered.records()]);
+        // New records go after the last valid one
+        recovered.insert(v[0]).unwrap();
+        let root = recovered.get_hash();
+        let records = recovered.records();
+        drop(recovered);
+        let reopened = wal::DurableTree::open(&truncated).unwrap();
+        assert_eq!((reopened.records(), reopened.get_hash()), (records, root));
+    }
+
+    // A corrupted byte stops the replay at the damaged record
+    let mut corrupted = log.clone();
+    corrupted[log.len() / 2] ^= 0xff;
+    std::fs::write(&truncated, &corrupted).unwrap();
+    let recovered = wal::DurableTree::open(&truncated).unwrap();
+    assert!(recovered.records() < v.len());
+    assert_eq!(recovered.get_hash(), prefix_roots[recovered.records()]);
+    drop(recovered);
+
+    // A valid record with an unknown operation is an error and nothing is cut
+    let record_length = log.len() / v.len();
+    let mut unknown = log[..record_length].to_vec();
+    unknown[4] = 7;
+    let checksum = unknown[..record_length - 8].to_vec().raw_hash();
+    unknown[record_length - 8..].copy_from_slice(&checksum[..8]);
+    unknown.extend(&log[..record_length]);
+    std::fs::write(&truncated, &unknown).unwrap();
+    assert!(wal::DurableTree::open(&truncated).is_err());
+    assert_eq!(std::fs::read(&truncated).unwrap(), unknown);
+
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+
+#[test]
+fn node_store_prune() {
+    let mut testree = MerkleTree::new();
+    let mut store = node_store::NodeStore::new();
+    let mut roots = vec![];
+    for round in 0..5 {
+        for i in 0..30 {
+            testree.insert(round * 10 + i);
+        }
+        roots.push(store.persist(&testree).unwrap());
+    }
+    for root in roots.iter() {
+        assert_eq!(store.load(root).unwrap().get_hash().as_ref(), Some(root));
+    }
+
+    // Retaining everything frees nothing
+    assert_eq!(store.prune(&roots), node_store::PruneReport::default());
+
+    let (nodes, bytes) = (store.node_count(), store.size_bytes());
+    let report = store.prune_keep_last(2);
+    assert!(report.nodes_reclaimed > 0);
+    assert_eq!(store.node_count(), nodes - report.nodes_reclaimed);
+    assert_eq!(store.size_bytes(), bytes - report.bytes_reclaimed);
+    assert_eq!(store.versions(), &roots[3..]);
+    assert!(store.load(&roots[0]).is_none());
+    let mut latest = store.load(&roots[4]).unwrap();
+    assert_eq!(latest.get_hash(), testree.get_hash());
+    assert_eq!(latest.contains(45), Some(3));
+}
+
+
+#[test]
+fn codec_round_trip() {
+    let mut testree = MerkleTree::new();
+    let v = rand_int_vec(8u64, 100, 10);
+    for &value in v.iter() {
+        testree.insert(value);
+    }
+    let mut restored = MerkleTree::from_bytes(&testree.to_bytes()).unwrap();
+    assert_eq!(restored.get_hash(), testree.get_hash());
+    assert!(MerkleTree::from_bytes(&testree.to_bytes()[..20]).is_err());
+
+    // Settings that change the root survive the round trip, secrets come from the template
+    let mut per_level = MerkleTree::with_empty_hashes(EmptyHashes::PerLevel);
+    let mut keyed = MerkleTree::keyed(b"codec");
+    let mut chained = MerkleTree::with_leaf_commitment(LeafCommitment::HashChain(b"codec".to_vec()));
+    for &value in v.iter() {
+        per_level.insert(value);
+        keyed.insert(value);
+        chained.insert(value);
+    }
+    assert_eq!(MerkleTree::from_bytes(&per_level.to_bytes()).unwrap().get_hash(), per_level.get_hash());
+    assert!(MerkleTree::from_bytes(&keyed.to_bytes()).is_err());
+    assert!(MerkleTree::from_bytes(&chained.to_bytes()).is_err());
+    let mut restored_keyed = MerkleTree::from_bytes_with(&keyed.to_bytes(), MerkleTree::keyed(b"codec")).unwrap();
+    assert_eq!(restored_keyed.get_hash(), keyed.get_hash());
+    assert_eq!(restored_keyed.contains(v[0]), keyed.contains(v[0]));
+    let template = MerkleTree::with_leaf_commitment(LeafCommitment::HashChain(b"codec".to_vec()));
+    assert_eq!(MerkleTree::from_bytes_with(&chained.to_bytes(), template).unwrap().get_hash(), chained.get_hash());
+    assert!(MerkleTree::from_bytes_with(&keyed.to_bytes(), MerkleTree::new()).is_err());
+
+    let root = testree.get_hash().unwrap();
+    for value in -12..12 {
+        let proof = codec::decode_proof(&codec::encode_proof(&restored.get_proof(value))).unwrap();
+        assert_eq!(verify_proof(&root, value, &proof), Some(testree.contains(value).unwrap_or(0)));
+        assert_eq!(verify_proof(&root, value.to_string(), &proof), verify_proof(&root, value, &proof));
+    }
+    assert_eq!(verify_proof(&root, 3, &testree.get_proof(4)), None);
+}
+
+
+#[test]
+fn dot_and_json_export() {
+    let mut testree = MerkleTree::new();
+    assert_eq!(testree.to_json(), "null");
+    for i in 0..6 {
+        testree.insert(i);
+    }
+    testree.insert(2);
+
+    let dot = testree.to_dot();
+    assert!(dot.starts_with("digraph merkle {"));
+    assert_eq!(dot.matches("shape=ellipse").count(), 6);
+    assert!(dot.contains("count 2"));
+    assert!(!dot.contains("color=red"));
+    let root_hex = to_hex(&testree.get_hash().unwrap());
+    assert!(dot.contains(&format!("n0 [label=\"{}\"", &root_hex[..8])));
+    // Root, every node down to the leaf and the edges between them
+    let highlighted = testree.to_dot_with_proof(2).matches("color=red").count();
+    assert_eq!(highlighted, 2 * (testree.get_proof(2).len() - 1) + 1);
+
+    let json = testree.to_json();
+    assert!(json.starts_with(&format!("{{\"kind\":\"inner\",\"hash\":\"{}\"", root_hex)));
+    assert_eq!(json.matches("\"kind\":\"leaf\"").count(), 6);
+    assert_eq!(json.matches('{').count(), json.matches('}').count());
+}
+
+
+#[test]
+fn tree_stats() {
+    let mut testree = MerkleTree::new();
+    assert_eq!(testree.stats(), stats::TreeStats::default());
+    testree.insert(1);
+    let single = testree.stats();
+    assert_eq!((single.leaf_nodes, single.inner_nodes, single.max_leaf_depth), (1, 0, 0));
+
+    let v = rand_int_vec(10u64, 300, 8);
+    for &value in v.iter() {
+        testree.insert(value);
+    }
+    let stats = testree.stats();
+    assert_eq!(stats.leaf_nodes, testree.len());
+    assert_eq!(stats.total_count, 1 + v.len() as u128);
+    // A tree where every inner node has two children would have leaves - 1 inner nodes
+    assert_eq!(stats.inner_nodes, stats.leaf_nodes - 1 + stats.single_child_nodes);
+    assert!(stats.single_child_chains <= stats.single_child_nodes);
+    assert_eq!(stats.depth_histogram.iter().sum::<usize>(), stats.leaf_nodes);
+    assert_eq!(stats.depth_histogram.len(), stats.max_leaf_depth + 1);
+    let deepest = testree.leaves().into_iter().map(|(hash, _)| {
+        testree.root.as_ref().unwrap().borrow().get_proof_hash(&HashBytes::new(hash), &EmptyHashes::Constant).len() - 1
+    });
+    assert_eq!(deepest.max(), Some(stats.max_leaf_depth));
+    assert!(stats.average_leaf_depth > 0.0 && stats.average_leaf_depth <= stats.max_leaf_depth as f64);
+    assert!(stats.heap_bytes > (stats.leaf_nodes + stats.inner_nodes) * 64);
+
+    // The total does not saturate at u64::MAX
+    testree.set_count(1, u64::MAX);
+    assert_eq!(testree.stats().total_count, testree.get_total());
+}
+
+
+#[test]
+fn absence_proofs() {
+    use absence_proof::AbsenceProof;
+    let mut testree = MerkleTree::new();
+    assert!(testree.prove_absence(1).is_none());
+    for i in 0..40 {
+        testree.insert(i);
+    }
+    let root = testree.get_hash().unwrap();
+    assert!(testree.prove_absence(5).is_none());
+
+    let (mut empty_slots, mut different_leaves) = (0, 0);
+    for key in 40..200 {
+        let proof = testree.prove_absence(key).unwrap();
+        assert!(proof.verify(&root, key));
+        match proof.absence {
+            AbsenceProof::EmptySlot => empty_slots += 1,
+            AbsenceProof::DifferentLeaf { .. } => different_leaves += 1,
+        }
+        // The same proof claims nothing about keys off its path
+        assert!(!proof.verify(&root, 3));
+    }
+    assert!(empty_slots > 0 && different_leaves > 0);
+
+    // A leaf hanging on the wrong side of the key's path does not prove absence,
+    // even under a root computed to match it
+    let key = (0..).find(|&k: &i32| k.hash().bit(0) == 0).unwrap();
+    let other = (0..).find(|&k: &i32| k.hash().bit(0) == 1).unwrap();
+    let misplaced = absence_proof::NonMembershipProof {
+        siblings: vec![ProofNode::Right(vec![1; 32])],
+        absence: AbsenceProof::DifferentLeaf { leaf_hash: other.raw_hash(), count: 1 },
+    };
+    let forged_root = roothash_from_proof(&mut misplaced.to_proof());
+    assert!(!misplaced.verify(&forged_root, key));
+}
+
+
+#[test]
+fn count_threshold_proofs() {
+    let mut testree = MerkleTree::with_leaf_commitment(LeafCommitment::HashChain(b"ledger secret".to_vec()));
+    let v = rand_int_vec(12u64, 60, 12);
+    for (i, &count) in v.iter().enumerate() {
+        for _ in 0..count {
+            testree.insert(i);
+        }
+    }
+    let root = testree.get_hash().unwrap();
+
+    // Leaves hide their counts: rebuilding with the count commitment gives another root
+    let plain = MerkleTree::from_leaves(testree.leaves());
+    assert_ne!(plain.get_hash().unwrap(), root);
+    assert!(plain.prove_count_at_least(0usize, 1).is_none());
+
+    for (i, &count) in v.iter().enumerate().filter(|&(_, &count)| count > 0) {
+        let count = count as u64;
+        for k in 0..=count {
+            let proof = testree.prove_count_at_least(i, k).unwrap();
+            assert!(threshold::verify_count_at_least(&root, i, k, &proof));
+            // The same witness does not stretch to a higher threshold or another key
+            assert!(!threshold::verify_count_at_least(&root, i, k + 1, &proof));
+            assert!(!threshold::verify_count_at_least(&root, i + 1, k, &proof));
+        }
+        assert!(testree.prove_count_at_least(i, count + 1).is_none());
+
+        // Neither the seed's own preimage nor anything but a 32-byte link stretches the chain
+        let mut forged = testree.prove_count_at_least(i, count).unwrap();
+        let mut preimage = b"ledger secret".to_vec();
+        preimage.extend(i.raw_hash());
+        forged.witness = preimage;
+        assert!(!threshold::verify_count_at_least(&root, i, count + 1, &forged));
+        forged.witness = vec![];
+        assert!(!threshold::verify_count_at_least(&root, i, 0, &forged));
+    }
+
+    // Plain proofs cannot show hash-chain leaves
+    assert!(testree.prove_absence(1000).is_none());
+    assert!(testree.prove_rank(&0usize.raw_hash()).is_none());
+    assert!(testree.sample(&mut rand_chacha::ChaCha8Rng::seed_from_u64(12)).is_none());
+    assert!(testree.prove_subtree(&[0, 1]).is_none());
+
+    // Chains stay short enough to build, and a persisted tree loads back into its template
+    assert_eq!(testree.insert_n(1000usize, MAX_CHAIN_COUNT + 1), Err(CountOverflow));
+    let mut store = node_store::NodeStore::new();
+    store.persist(&testree).unwrap();
+    assert!(store.load(&root).is_none());
+    let template = MerkleTree::with_leaf_commitment(LeafCommitment::HashChain(b"ledger secret".to_vec()));
+    let mut loaded = store.load_with(&root, template).unwrap();
+    assert_eq!(loaded.get_hash().unwrap(), root);
+    let (i, _) = v.iter().enumerate().find(|&(_, &count)| count > 0).unwrap();
+    loaded.insert(i);
+    testree.insert(i);
+    assert_eq!(loaded.get_hash(), testree.get_hash());
+    assert!(threshold::verify_count_at_least(&loaded.get_hash().unwrap(), i, 1, &loaded.prove_count_at_least(i, 1).unwrap()));
+    let root = testree.get_hash().unwrap();
+
+    // Counts set directly commit to the same chain as repeated inserts
+    let rebuilt = {
+        let mut tree = MerkleTree::with_leaf_commitment(LeafCommitment::HashChain(b"ledger secret".to_vec()));
+        for (hash, count) in testree.leaves() {
+            tree.write_count(HashBytes::new(hash), count);
+        }
+        tree
+    };
+    assert_eq!(rebuilt.get_hash().unwrap(), root);
+}
+
+
+#[test]
+fn keyed_tree() {
+    // RFC 4231, test case 2
+    assert_eq!(
+        to_hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+        "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+    );
+
+    let (mut keyed, mut plain) = (MerkleTree::keyed(b"tree key"), MerkleTree::new());
+    for i in 0..50 {
+        keyed.insert(i);
+        plain.insert(i);
+    }
+    keyed.insert(7);
+    assert_eq!(keyed.contains(7), Some(2));
+    assert_eq!(keyed.contains(70), None);
+    assert_ne!(keyed.get_hash(), plain.get_hash());
+    assert_ne!(keyed.key_digest(&7), 7.raw_hash());
+
+    let root = keyed.get_hash().unwrap();
+    let proof = keyed.get_proof(7);
+    assert_eq!(verify_proof(&root, Prehashed(keyed.key_digest(&7)), &proof), Some(2));
+    // Without the secret the leaf cannot be matched to its key
+    assert_eq!(verify_proof(&root, 7, &proof), None);
+    let leaf_hash = match proof.last() {
+        Some(ProofNode::Leaf(leaf_hash, _)) => leaf_hash.clone(),
+        _ => panic!("expected a leaf"),
+    };
+    assert!((-1000..1000).all(|guess: i32| guess.raw_hash() != leaf_hash));
+}
+
+
+#[test]
+fn wide_counts() {
+    // Counts that fit the old field hash exactly as before
+    let key_hash = 3.hash();
+    let mut legacy = 5i32.to_le_bytes().to_vec();
+    legacy.extend(key_hash.bytes());
+    assert_eq!(treenode::hash_from_version(5, &key_hash).bytes(), &legacy.raw_hash());
+    assert_ne!(
+        treenode::hash_from_version(1 << 32, &key_hash),
+        treenode::hash_from_version(0, &key_hash)
+    );
+
+    let mut testree = MerkleTree::new();
+    for i in 0..20 {
+        testree.insert(i);
+    }
+    testree.write_count(3.hash(), LEGACY_MAX_COUNT);
+    assert_eq!(testree.try_insert(3), Ok(LEGACY_MAX_COUNT + 1));
+    let root = testree.get_hash().unwrap();
+    let proof = codec::decode_proof(&codec::encode_proof(&testree.get_proof(3))).unwrap();
+    assert_eq!(verify_proof(&root, 3, &proof), Some(LEGACY_MAX_COUNT + 1));
+    let restored = MerkleTree::from_bytes(&testree.to_bytes()).unwrap();
+    assert_eq!(restored.get_hash().unwrap(), root);
+
+    // Overflowing leaves the tree untouched
+    testree.write_count(4.hash(), u64::MAX);
+    let root = testree.get_hash();
+    assert_eq!(testree.try_insert(4), Err(CountOverflow));
+    assert_eq!(testree.get_hash(), root);
+}
+
+
+#[test]
+fn set_and_remove_counts() {
+    let v = rand_int_vec(14u64, 300, 20);
+    let (mut inserted, mut counted) = (MerkleTree::new(), MerkleTree::new());
+    for (i, &count) in v.iter().enumerate().filter(|&(_, &count)| count > 0) {
+        for _ in 0..count {
+            inserted.insert(i);
+        }
+        if i % 2 == 0 {
+            counted.set_count(i, count as u64);
+        } else {
+            assert_eq!(counted.insert_n(i, count as u64), Ok(count as u64));
+        }
+    }
+    assert_eq!(inserted.get_hash(), counted.get_hash());
+    assert_eq!(counted.get_count(5), std::cmp::max(v[5], 0) as u64);
+    assert_eq!(counted.get_count(1000), 0);
+    assert_eq!(counted.insert_n(1000, u64::MAX), Ok(u64::MAX));
+    assert_eq!(counted.insert_n(1000, 1), Err(CountOverflow));
+    counted.set_count(1000, 0);
+    assert_eq!(inserted.get_hash(), counted.get_hash());
+
+    // Removing keys leaves the same shape as never inserting them
+    let snapshot = counted.share();
+    let mut remaining = MerkleTree::new();
+    for (i, &count) in v.iter().enumerate().filter(|&(_, &count)| count > 0) {
+        if i % 3 == 0 {
+            counted.set_count(i, 0);
+        } else {
+            remaining.set_count(i, count as u64);
+        }
+    }
+    assert_eq!(counted.get_hash(), remaining.get_hash());
+    assert_eq!(counted.len(), remaining.len());
+    assert_eq!(snapshot.get_hash(), inserted.get_hash());
+
+    for (i, _) in v.iter().enumerate() {
+        counted.set_count(i, 0);
+    }
+    assert!(counted.is_empty());
+}
+
+
+#[test]
+fn per_level_empty_hashes() {
+    let empty = EmptyHashes::PerLevel;
+    assert_eq!(empty.at_depth(HASH_LENGTH), &[0; 32][..]);
+    assert_eq!(empty.at_depth(HASH_LENGTH - 1), &[0u8; 64].to_vec().raw_hash()[..]);
+    let mut child = empty.at_depth(1).to_vec();
+    child.extend(empty.at_depth(1));
+    assert_eq!(empty.at_depth(0), &child.raw_hash()[..]);
+
+    let (mut sparse, mut constant) = (MerkleTree::with_empty_hashes(empty.clone()), MerkleTree::new());
+    let v = rand_int_vec(17u64, 200, 3);
+    for (i, &count) in v.iter().enumerate().filter(|&(_, &count)| count > 0) {
+        sparse.set_count(i, count as u64);
+        constant.set_count(i, count as u64);
+    }
+    let root = sparse.get_hash().unwrap();
+    assert_ne!(Some(root.clone()), constant.get_hash());
+
+    let mut needs_scheme = false;
+    for (i, &count) in v.iter().enumerate() {
+        let proof = sparse.get_proof(i);
+        assert_eq!(verify_proof_with(&root, i, &proof, &empty), Some(std::cmp::max(count, 0) as u64));
+        needs_scheme |= verify_proof(&root, i, &proof).is_none();
+        if count <= 0 {
+            assert!(sparse.prove_absence(i).unwrap().verify_with(&root, i, &empty));
+        }
+    }
+    assert!(needs_scheme);
+
+    // Removing a key restores the previous root
+    sparse.insert(1000);
+    sparse.set_count(1000, 0);
+    assert_eq!(sparse.get_hash().unwrap(), root);
+
+    // Roots and proofs are those of a standard 256-level sparse Merkle tree
+    let leaves: Vec<(HashBytes, u64)> = sparse.leaves().into_iter().map(|(hash, count)| (HashBytes::new(hash), count)).collect();
+    assert_eq!(root, sparse_merkle_root(&leaves, 0));
+    for key in [0usize, 1000] {
+        assert_eq!(sparse.get_proof(key).len(), HASH_LENGTH + 1);
+    }
+    let mut single = MerkleTree::with_empty_hashes(empty.clone());
+    single.insert(5usize);
+    assert_eq!(single.get_hash().unwrap(), sparse_merkle_root(&[(5usize.hash(), 1)], 0));
+    single.set_count(5usize, 0);
+    assert!(single.is_empty());
+
+    // Partial trees place new keys at full depth too
+    let block = [3usize, 1000, 1001, 1000];
+    let proofs: Vec<Vec<ProofNode>> = block.iter().map(|&key| sparse.get_proof(key)).collect();
+    let mut partial = partial_tree::PartialTree::from_proofs_with(&root, &proofs, &empty).unwrap();
+    for &key in block.iter() {
+        partial.insert(key).unwrap();
+        sparse.insert(key);
+        assert_eq!(partial.get_hash(), sparse.get_hash().unwrap());
+    }
+}
+
+
+#[test]
+fn deferred_hashing() {
+    let v = rand_int_vec(19u64, 400, 5);
+    let (mut eager, mut deferred) = (MerkleTree::new(), MerkleTree::new());
+    deferred.set_deferred_hashing(true);
+    for (i, &count) in v.iter().enumerate() {
+        for _ in 0..count {
+            eager.insert(i);
+            deferred.insert(i);
+        }
+    }
+    assert_eq!(deferred.get_hash(), eager.get_hash());
+
+    // Snapshots taken with dirty paths hash to their own contents
+    let snapshot = deferred.share();
+    for i in 0..50 {
+        deferred.set_count(i, 0);
+        eager.set_count(i, 0);
+    }
+    deferred.insert(1000);
+    eager.insert(1000);
+    let root = eager.get_hash().unwrap();
+    for i in (0..v.len()).step_by(7) {
+        let proof = deferred.get_proof(i);
+        assert_eq!(proof, eager.get_proof(i));
+        assert!(verify_proof(&root, i, &proof).is_some());
+    }
+    assert_eq!(deferred.get_hash(), Some(root));
+    assert_eq!(snapshot.get_hash(), MerkleTree::from_leaves(snapshot.leaves()).get_hash());
+    assert!(snapshot.get_hash() != deferred.get_hash());
+
+    deferred.set_deferred_hashing(false);
+    deferred.insert(1001);
+    eager.insert(1001);
+    assert_eq!(deferred.get_hash(), eager.get_hash());
+}
+
+
+#[test]
+fn paths_near_depth_limit() {
+    // Keys sharing all but their last bits sit close to the 256-level limit
+    let keys: Vec<Prehashed> = (0..=255u8)
+        .map(|last| {
+            let mut hash = vec![0; 32];
+            hash[31] = last;
+            Prehashed(hash)
+        })
+        .collect();
+    let mut testree = MerkleTree::new();
+    for key in keys.iter().cloned() {
+        testree.insert(key.clone());
+        testree.insert(key);
+    }
+    let root = testree.get_hash().unwrap();
+    assert_eq!(MerkleTree::from_leaves(testree.leaves()).get_hash(), Some(root.clone()));
+    for key in keys.iter().cloned() {
+        assert_eq!(testree.contains(key.clone()), Some(2));
+        let proof = testree.get_proof(key.clone());
+        assert_eq!(proof.len(), HASH_LENGTH + 1);
+        assert_eq!(verify_proof(&root, key, &proof), Some(2));
+    }
+    let mut absent = vec![0; 32];
+    absent[30] = 1;
+    assert_eq!(testree.contains(Prehashed(absent.clone())), None);
+    assert_eq!(verify_proof(&root, Prehashed(absent.clone()), &testree.get_proof(Prehashed(absent))), Some(0));
+
+    for key in keys.into_iter().rev() {
+        testree.set_count(key, 0);
+    }
+    assert!(testree.is_empty());
+}
+
+
+#[test]
+fn partial_tree_from_proofs() {
+    use partial_tree::{PartialTree, PartialTreeError};
+
+    let mut testree = MerkleTree::new();
+    let v = rand_int_vec(21u64, 300, 3);
+    for (i, &count) in v.iter().enumerate() {
+        for _ in 0..count {
+            testree.insert(i);
+        }
+    }
+    let root = testree.get_hash().unwrap();
+
+    // A block touching present keys, absent keys and keys it adds itself
+    let block: Vec<usize> = vec![3, 3, 17, 250, 299, 1000, 1001, 1000, 4000, 17];
+    let proofs: Vec<Vec<ProofNode>> = block.iter().map(|&key| testree.get_proof(key)).collect();
+    let mut partial = PartialTree::from_proofs(&root, &proofs).unwrap();
+    assert_eq!(partial.get_hash(), root);
+    for &key in block.iter() {
+        assert_eq!(partial.contains(key), Ok(testree.contains(key)));
+    }
+
+    for &key in block.iter() {
+        let count = partial.insert(key).unwrap();
+        testree.insert(key);
+        assert_eq!(Some(count), testree.contains(key));
+        assert_eq!(partial.get_hash(), testree.get_hash().unwrap());
+    }
+    let uncovered = (0..300).find(|&key| partial.contains(key).is_err()).unwrap();
+    assert_eq!(partial.insert(uncovered), Err(PartialTreeError::NotCovered));
+
+    let mut forged = testree.get_proof(3usize);
+    if let Some(ProofNode::Leaf(_, count)) = forged.last_mut() {
+        *count += 1;
+    }
+    let root = testree.get_hash().unwrap();
+    assert_eq!(PartialTree::from_proofs(&root, &[forged]).err(), Some(PartialTreeError::InvalidProof));
+    assert_eq!(PartialTree::from_proofs(&root, &[vec![]]).err(), Some(PartialTreeError::InvalidProof));
+}
+
+
+#[test]
+fn subtree_proofs_and_extraction() {
+    let mut testree = MerkleTree::new();
+    for i in 0..200usize {
+        testree.insert_n(i, 1 + i as u64 % 3).unwrap();
+    }
+    let root = testree.get_hash().unwrap();
+    let on_prefix = |key: usize, prefix: &[u8]| (0..prefix.len()).all(|level| key.hash().bit(level) == prefix[level]);
+
+    for shard in 0..8u8 {
+        let prefix = vec![shard >> 2 & 1, shard >> 1 & 1, shard & 1];
+        let subtree_hash = testree.subtree_hash(&prefix);
+        let proof = testree.prove_subtree(&prefix).unwrap();
+        assert!(proof.verify(&root, &prefix, subtree_hash.as_deref()));
+        assert!(!proof.verify(&root, &prefix, None));
+
+        let mut extracted = testree.extract_subtree(&prefix);
+        assert_eq!(extracted.subtree_hash(&prefix), subtree_hash);
+        let keys: Vec<usize> = (0..200).filter(|&key| on_prefix(key, &prefix)).collect();
+        assert_eq!(extracted.len(), keys.len());
+        for &key in keys.iter() {
+            assert_eq!(proof.stitch(&extracted.get_proof(key)), testree.get_proof(key));
+        }
+        // The shard tree is a copy, inserting into it leaves the global tree alone
+        extracted.insert(keys[0]);
+        assert_eq!(testree.get_hash().unwrap(), root);
+    }
+
+    // Deep prefixes run into lone leaves and empty slots
+    let key = 7usize;
+    let bits: Vec<u8> = (0..20).map(|level| key.hash().bit(level)).collect();
+    assert_eq!(testree.subtree_hash(&bits), Some(hash_from_version(2, &key.hash()).bytes().clone()));
+    let proof = testree.prove_subtree(&bits).unwrap();
+    assert!(proof.siblings.len() < bits.len());
+    assert!(proof.verify(&root, &bits, testree.subtree_hash(&bits).as_deref()));
+    let mut extracted = testree.extract_subtree(&bits);
+    assert_eq!(proof.stitch(&extracted.get_proof(key)), testree.get_proof(key));
+
+    let mut off_path = bits.clone();
+    off_path[19] ^= 1;
+    assert_eq!(testree.subtree_hash(&off_path), None);
+    let proof = testree.prove_subtree(&off_path).unwrap();
+    assert!(proof.verify(&root, &off_path, None));
+    assert!(testree.extract_subtree(&off_path).is_empty());
+    assert!(!proof.verify(&root, &bits, None));
+}
+
+
+#[test]
+fn sharded_tree_matches_single_tree() {
+    use sharded::ShardedMerkleTree;
+
+    let v = rand_int_vec(46u64, 400, 3);
+    let mut testree = MerkleTree::new();
+    for (i, &count) in v.iter().enumerate() {
+        for _ in 0..count {
+            testree.insert(i);
+        }
+    }
+
+    let sharded = ShardedMerkleTree::new(4);
+    std::thread::scope(|scope| {
+        for thread in 0..4 {
+            let (sharded, v) = (&sharded, &v);
+            scope.spawn(move || {
+                for (i, &count) in v.iter().enumerate().skip(thread).step_by(4) {
+                    for _ in 0..count {
+                        sharded.insert(i);
+                    }
+                }
+            });
+        }
+    });
+    assert_eq!(sharded.get_hash(), testree.get_hash());
+    assert_eq!(sharded.len(), testree.len());
+    assert_eq!(sharded.get_count(7usize), testree.get_count(7usize));
+
+    // Few keys leave most shards empty and lone leaves above the shard depth
+    for prefix_bits in [0, 1, 3, 8] {
+        for empty in [EmptyHashes::Constant, EmptyHashes::PerLevel] {
+            let sharded = ShardedMerkleTree::with_empty_hashes(prefix_bits, empty.clone());
+            let mut testree = MerkleTree::with_empty_hashes(empty);
+            assert_eq!(sharded.get_hash(), None);
+            for key in 0..5usize {
+                sharded.insert(key);
+                testree.insert(key);
+                assert_eq!(sharded.get_hash(), testree.get_hash());
+            }
+            for key in 0..5usize {
+                sharded.set_count(key, 0);
+                testree.set_count(key, 0);
+                assert_eq!(sharded.get_hash(), testree.get_hash());
+            }
+            assert!(sharded.is_empty());
+        }
+    }
+
+    // Node commitments and keyed hashing carry over to the assembled root
+    for node_commitment in [NodeCommitment::Sum, NodeCommitment::Counted] {
+        let settings = TreeSettings { node_commitment, key_secret: Some(b"shards".to_vec()), ..TreeSettings::default() };
+        let sharded = ShardedMerkleTree::with_settings(3, settings.clone());
+        let mut testree = MerkleTree::with_settings(settings);
+        for (i, &count) in v.iter().enumerate().filter(|&(_, &count)| count > 0) {
+            sharded.set_count(i, count as u64);
+            testree.set_count(i, count as u64);
+        }
+        assert_eq!(sharded.get_hash(), testree.get_hash());
+        assert_eq!(sharded.get_count(7usize), testree.get_count(7usize));
+    }
+}
+
+
+#[test]
+fn sum_tree_commits_to_total() {
+    use sum_tree::verify_sum_proof;
+
+    let mut testree = MerkleTree::with_node_commitment(NodeCommitment::Sum);
+    let mut plain = MerkleTree::new();
+    rand_counts(&mut plain, 47u64, 200, 4);
+    let total = rand_counts(&mut testree, 47u64, 200, 4) + u64::MAX as u128;
+    testree.insert_n(5000usize, u64::MAX).unwrap();
+    let root = testree.get_hash().unwrap();
+    assert_eq!(testree.get_total(), total);
+    assert_ne!(Some(root.clone()), plain.get_hash());
+
+    for key in (0..200usize).chain([5000, 7000]) {
+        let proof = testree.get_sum_proof(key);
+        assert_eq!(verify_sum_proof(&root, total, key, &proof), Some(testree.get_count(key)));
+        assert_eq!(verify_sum_proof(&root, total - 1, key, &proof), None);
+    }
+
+    // Understating a sibling total is caught by the root
+    let mut forged = testree.get_sum_proof(3usize);
+    forged.sibling_totals[0] -= 1;
+    assert_eq!(verify_sum_proof(&root, total - 1, 3usize, &forged), None);
+
+    testree.set_count(5000usize, 0);
+    assert_eq!(testree.get_total(), total - u64::MAX as u128);
+
+    // Node commitments combine with the other settings
+    let settings = TreeSettings {
+        node_commitment: NodeCommitment::Sum,
+        empty_hashes: EmptyHashes::PerLevel,
+        ..TreeSettings::default()
+    };
+    let mut combined = MerkleTree::with_settings(settings.clone());
+    for i in 0..20usize {
+        combined.set_count(i, i as u64 + 1);
+    }
+    assert_eq!(combined.settings(), settings);
+    let root = combined.get_hash().unwrap();
+    let proof = combined.get_sum_proof(7usize);
+    assert_eq!(sum_tree::verify_sum_proof_with(&root, 210, 7usize, &proof, &EmptyHashes::PerLevel), Some(8));
+
+    // Saving and persisting keep the node commitment
+    let loaded = MerkleTree::from_bytes(&combined.to_bytes()).unwrap();
+    assert_eq!(loaded.settings(), settings);
+    assert_eq!(loaded.get_hash(), Some(root.clone()));
+    let mut store = node_store::NodeStore::new();
+    store.persist(&combined);
+    assert!(store.load(&root).is_none());
+    let reloaded = store.load_with(&root, MerkleTree::with_settings(settings)).unwrap();
+    assert_eq!(reloaded.get_hash(), Some(root));
+
+    // Proofs that only recompute plain inner hashes are refused
+    assert_eq!(combined.prove_absence(1000usize), None);
+    assert_eq!(combined.prove_subtree(&[]), None);
+    assert_eq!(combined.prove_rank(&0usize.raw_hash()), None);
+    let proof = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| combined.get_proof(7usize)));
+    assert!(proof.is_err());
+}
+
+
+#[test]
+fn weighted_and_uniform_sampling() {
+    let mut testree = MerkleTree::new();
+    let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(48);
+    assert_eq!(testree.sample(&mut rng), None);
+
+    testree.set_count(0usize, 990);
+    for key in 1..11usize {
+        testree.insert(key);
+    }
+    let root = testree.get_hash().unwrap();
+    let heavy = 0usize.raw_hash();
+
+    let mut weighted_hits = 0;
+    let mut uniform_hits = 0;
+    for _ in 0..1000 {
+        let (key_hash, count, proof) = testree.sample(&mut rng).unwrap();
+        assert_eq!(verify_proof(&root, Prehashed(key_hash.clone()), &proof), Some(count));
+        weighted_hits += (key_hash == heavy) as usize;
+
+        let (key_hash, count, proof) = testree.sample_uniform(&mut rng).unwrap();
+        assert_eq!(verify_proof(&root, Prehashed(key_hash.clone()), &proof), Some(count));
+        uniform_hits += (key_hash == heavy) as usize;
+    }
+    assert!(weighted_hits > 970, "{}", weighted_hits);
+    assert!(uniform_hits > 50 && uniform_hits < 150, "{}", uniform_hits);
+
+    testree.set_count(0usize, 0);
+    assert_eq!(testree.len(), testree.leaves().len());
+    for _ in 0..100 {
+        assert_ne!(testree.sample(&mut rng).unwrap().0, heavy);
+    }
+}
+
+
+#[test]
+fn rank_and_select_with_proofs() {
+    use rank::{verify_rank, verify_select, Counts};
+
+    let mut testree = MerkleTree::with_node_commitment(NodeCommitment::Counted);
+    rand_counts(&mut testree, 49u64, 300, 3);
+    let root = testree.get_hash().unwrap();
+    let leaves = testree.leaves();
+    assert_eq!(testree.select(leaves.len() as u64), None);
+
+    let mut before = Counts::default();
+    for (index, (key_hash, count)) in leaves.iter().enumerate() {
+        let index = index as u64;
+        assert_eq!(testree.select(index), Some((key_hash.clone(), *count)));
+        assert_eq!(testree.rank(key_hash), Some(before));
+
+        let proof = testree.prove_select(index).unwrap();
+        assert_eq!(verify_rank(&root, key_hash, &proof), Some((before, *count)));
+        assert_eq!(verify_select(&root, index, key_hash, &proof), Some(*count));
+        assert_eq!(verify_select(&root, index + 1, key_hash, &proof), None);
+        before = Counts { keys: before.keys + 1, total: before.total + *count as u128 };
+    }
+
+    // Absent keys rank between their neighbours
+    for key in 1000..1020usize {
+        let key_hash = key.raw_hash();
+        let expected = leaves.iter().filter(|(hash, _)| *hash < key_hash).fold(Counts::default(), |rank, (_, count)| Counts {
+            keys: rank.keys + 1,
+            total: rank.total + *count as u128,
+        });
+        assert_eq!(testree.rank(&key_hash), Some(expected));
+        let proof = testree.prove_rank(&key_hash).unwrap();
+        assert_eq!(verify_rank(&root, &key_hash, &proof), Some((expected, 0)));
+    }
+
+    // Only full key hashes have a rank
+    assert_eq!(testree.rank(&[]), None);
+    assert_eq!(testree.rank(&[7]), None);
+    assert_eq!(testree.prove_rank(&[7]), None);
+
+    let mut forged = testree.prove_select(10).unwrap();
+    for counts in forged.sibling_counts.iter_mut() {
+        counts.keys += 1;
+    }
+    assert_eq!(verify_select(&root, 10, &leaves[10].0, &forged), None);
+}
+
+
+#[test]
+fn range_proofs_cover_interval() {
+    use range_proof::RangeNode;
+
+    let mut testree = MerkleTree::new();
+    rand_counts(&mut testree, 50u64, 500, 2);
+    let root = testree.get_hash().unwrap();
+    let leaves = testree.leaves();
+    let in_range = |lo: &[u8], hi: &[u8]| -> Vec<(Vec<u8>, u64)> {
+        leaves.iter().filter(|(hash, _)| hash.as_slice() >= lo && hash.as_slice() <= hi).cloned().collect()
+    };
+
+    let mut bounds = vec![
+        (leaves[10].0.clone(), leaves[40].0.clone()),
+        (leaves[7].0.clone(), leaves[7].0.clone()),
+        (vec![0; 32], vec![255; 32]),
+        (1000usize.raw_hash(), 1000usize.raw_hash()),
+    ];
+    for (a, b) in [(2000usize, 2001usize), (2002, 2003), (2004, 2005)] {
+        let (a, b) = (a.raw_hash(), b.raw_hash());
+        bounds.push(if a <= b { (a, b) } else { (b, a) });
+    }
+    for (lo, hi) in bounds.iter() {
+        let proof = testree.prove_range(lo, hi).unwrap();
+        assert_eq!(proof.verify(&root, lo, hi), Some(in_range(lo, hi)));
+    }
+    assert_eq!(testree.prove_range(&leaves[40].0, &leaves[10].0), None);
+    assert_eq!(testree.prove_range(&[], &[]), None);
+    assert_eq!(testree.prove_range(&[0], &[255]), None);
+
+    // Hiding a leaf of the interval behind its hash is rejected
+    let (lo, hi) = (&leaves[10].0, &leaves[40].0);
+    let mut proof = testree.prove_range(lo, hi).unwrap();
+    let mut node = &mut proof.root;
+    let mut level = 0;
+    while let RangeNode::Inner(left, right) = node {
+        node = if HashBytes::new(lo.clone()).bit(level) == 0 { left } else { right };
+        level += 1;
+    }
+    let hidden = match node {
+        RangeNode::Leaf(key_hash, count) => hash_from_version(*count, &HashBytes::new(key_hash.clone())).bytes().clone(),
+        _ => panic!("the path of lo ends in its leaf"),
+    };
+    *node = RangeNode::Hash(hidden);
+    assert_eq!(proof.verify(&root, lo, hi), None);
+}
+
+
 
 fn rand_bool_vec(seed: u64, n: usize) -> Vec<bool> {
     let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(seed);
@@ -132,6 +1269,33 @@ fn rand_int_vec(seed: u64, n: usize, max_value: i32) -> Vec<i32> {
     v
 }
 
+// Sets key i to the i-th value of `rand_int_vec` where positive, returning the total count
+fn rand_counts(tree: &mut MerkleTree, seed: u64, n: usize, max_value: i32) -> u128 {
+    let mut total = 0;
+    for (i, &count) in rand_int_vec(seed, n, max_value).iter().enumerate() {
+        if count > 0 {
+            tree.set_count(i, count as u64);
+            total += count as u128;
+        }
+    }
+    total
+}
+
+// Root of the subtree at `depth` of a standard sparse Merkle tree over per-level empty hashes,
+// built without any compression
+fn sparse_merkle_root(leaves: &[(HashBytes, u64)], depth: usize) -> Vec<u8> {
+    match leaves {
+        [] => EmptyHashes::PerLevel.at_depth(depth).to_vec(),
+        [(hash, count)] if depth == HASH_LENGTH => treenode::hash_from_version(*count, hash).bytes().clone(),
+        _ => {
+            let (left, right): (Vec<_>, Vec<_>) = leaves.iter().cloned().partition(|(hash, _)| hash.bit(depth) == 0);
+            let mut bytes = sparse_merkle_root(&left, depth + 1);
+            bytes.extend(sparse_merkle_root(&right, depth + 1));
+            bytes.raw_hash()
+        }
+    }
+}
+
 /*
 
 fn random_tree(seed1: u64) -> TreeNode {
@@ -0,0 +1,187 @@
+//! Proofs that a root transition was caused by a single leaf update.
+//!
+//! The proof is the sibling path of the key taken before the update. The verifier
+//! recomputes the old root from it, then replaces only the terminal leaf and checks
+//! that the same siblings produce the new root.
+
+use super::*;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpdateProof {
+    /// Proof of the key in the old tree, empty if the old tree was empty.
+    pub path: Vec<ProofNode>,
+}
+
+/// Single-leaf proofs chained through the intermediate roots.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchUpdateProof {
+    pub steps: Vec<(UpdateProof, Vec<u8>)>,
+}
+
+impl MerkleTree {
+    /// Panics on a tree without plain proofs, see `get_proof`.
+    pub fn insert_with_proof<T: Hashable + Debug>(&mut self, key: T) -> UpdateProof {
+        assert!(self.has_proofs(NodeCommitment::Hash), "tree has no plain proofs");
+        let hash = self.hash_key(&key);
+        let path = match self.root_node() {
+            Some(node) => node.borrow().get_proof_hash(&hash, &self.empty_hashes),
+            None => vec![],
+        };
+        self.update_count(hash, |count| count.checked_add(1)).expect("count overflow");
+        UpdateProof { path }
+    }
+
+    pub fn insert_batch_with_proof<T: Hashable + Debug>(&mut self, keys: Vec<T>) -> BatchUpdateProof {
+        let steps = keys
+            .into_iter()
+            .map(|key| {
+                let proof = self.insert_with_proof(key);
+                (proof, self.get_hash().unwrap())
+            })
+            .collect();
+        BatchUpdateProof { steps }
+    }
+}
+
+pub fn verify_update<T: Hashable>(
+    old_root: Option<&[u8]>,
+    new_root: &[u8],
+    key: T,
+    old_count: u64,
+    new_count: u64,
+    proof: &UpdateProof,
+) -> bool {
+    verify_update_with(old_root, new_root, key, old_count, new_count, proof, &EmptyHashes::Constant)
+}
+
+/// `verify_update` for a tree built with the given empty hashes.
+pub fn verify_update_with<T: Hashable>(
+    old_root: Option<&[u8]>,
+    new_root: &[u8],
+    key: T,
+    old_count: u64,
+    new_count: u64,
+    proof: &UpdateProof,
+    empty: &EmptyHashes,
+) -> bool {
+    verify_update_hash(old_root, new_root, &key.hash(), old_count, new_count, proof, empty)
+}
+
+/// `updates` holds (key, old count, new count) in the order the inserts were applied.
+pub fn verify_batch_update<T: Hashable>(
+    old_root: Option<&[u8]>,
+    new_root: &[u8],
+    updates: Vec<(T, u64, u64)>,
+    proof: &BatchUpdateProof,
+) -> bool {
+    verify_batch_update_with(old_root, new_root, updates, proof, &EmptyHashes::Constant)
+}
+
+/// `verify_batch_update` for a tree built with the given empty hashes.
+pub fn verify_batch_update_with<T: Hashable>(
+    old_root: Option<&[u8]>,
+    new_root: &[u8],
+    updates: Vec<(T, u64, u64)>,
+    proof: &BatchUpdateProof,
+    empty: &EmptyHashes,
+) -> bool {
+    if updates.len() != proof.steps.len() {
+        return false;
+    }
+    let mut root = old_root.map(|root| root.to_vec());
+    for ((key, old_count, new_count), (step, step_root)) in updates.into_iter().zip(proof.steps.iter()) {
+        if !verify_update_with(root.as_deref(), step_root, key, old_count, new_count, step, empty) {
+            return false;
+        }
+        root = Some(step_root.clone());
+    }
+    root.as_deref() == Some(new_root)
+}
+
+pub(crate) fn verify_update_hash(
+    old_root: Option<&[u8]>,
+    new_root: &[u8],
+    key_hash: &HashBytes,
+    old_count: u64,
+    new_count: u64,
+    proof: &UpdateProof,
+    empty: &EmptyHashes,
+) -> bool {
+    if new_count == 0 {
+        return false;
+    }
+    let new_leaf = ProofNode::Leaf(key_hash.bytes().clone(), new_count);
+    let (terminal, siblings) = match proof.path.split_last() {
+        Some(split) => split,
+        // The key became the only leaf of a previously empty tree
+        None => {
+            let mut new_path = vec![];
+            if empty.full_depth() {
+                push_empty_siblings(&mut new_path, key_hash, empty);
+            }
+            new_path.push(new_leaf);
+            return old_root.is_none() && old_count == 0 && roothash_from_proof_with(&mut new_path, empty) == new_root;
+        }
+    };
+    let old_root = match old_root {
+        Some(old_root) => old_root,
+        None => return false,
+    };
+
+    // Only a leaf or an empty slot can end the path
+    if !follows_key_path(siblings, key_hash) || !matches!(terminal, ProofNode::Leaf(..) | ProofNode::None) {
+        return false;
+    }
+    if roothash_from_proof_with(&mut proof.path.clone(), empty) != old_root {
+        return false;
+    }
+
+    let mut new_path = siblings.to_vec();
+    match terminal {
+        ProofNode::Leaf(leaf_hash, count) if leaf_hash == key_hash.bytes() => {
+            if *count != old_count {
+                return false;
+            }
+        }
+        // With full-depth leaves the paths of two keys never end in the same slot
+        ProofNode::Leaf(leaf_hash, count) if leaf_hash.len() == key_hash.bytes().len() && !empty.full_depth() => {
+            if old_count != 0 {
+                return false;
+            }
+            // The other leaf moves down until its path splits from the key's path
+            let other = HashBytes::new(leaf_hash.clone());
+            let mut level = siblings.len();
+            if (0..level).any(|i| other.bit(i) != key_hash.bit(i)) {
+                return false;
+            }
+            while other.bit(level) == key_hash.bit(level) {
+                new_path.push(sibling_on_path(key_hash, level, empty.at_depth(level + 1).to_vec()));
+                level += 1;
+                if level >= HASH_LENGTH {
+                    return false;
+                }
+            }
+            let other_hash = hash_from_version(*count, &other).bytes().clone();
+            new_path.push(sibling_on_path(key_hash, level, other_hash));
+        }
+        ProofNode::None => {
+            if old_count != 0 {
+                return false;
+            }
+            if empty.full_depth() {
+                push_empty_siblings(&mut new_path, key_hash, empty);
+            }
+        }
+        _ => return false,
+    }
+    new_path.push(new_leaf);
+    roothash_from_proof_with(&mut new_path, empty) == new_root
+}
+
+fn sibling_on_path(key_hash: &HashBytes, level: usize, sibling: Vec<u8>) -> ProofNode {
+    if key_hash.bit(level) == 1 {
+        ProofNode::Left(sibling)
+    } else {
+        ProofNode::Right(sibling)
+    }
+}
@@ -0,0 +1,140 @@
+//! Write-ahead log for a disk-backed `MerkleTree`.
+//!
+//! Every insert is appended to the log and synced before it is applied to the tree.
+//! A record is `[payload length: u32 LE][payload][checksum: 8 bytes]`, the checksum
+//! being the first bytes of SHA256 over the length and the payload. Reopening replays
+//! the records up to the first incomplete or corrupted one and cuts the log there.
+//! A valid record that cannot be applied is an error instead, and the log is left as is.
+
+use super::*;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const CHECKSUM_LENGTH: usize = 8;
+const OP_INSERT: u8 = 0;
+
+pub struct DurableTree {
+    tree: MerkleTree,
+    log: File,
+    records: usize,
+    /// Offset just past the last acknowledged record.
+    end: u64,
+    /// Set when a failed append could not be rolled back, so the log may hold an
+    /// insert the tree never saw.
+    poisoned: bool,
+}
+
+impl DurableTree {
+    /// Opens or creates the log at `path` and rebuilds the tree from it.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut log = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        let mut bytes = vec![];
+        log.read_to_end(&mut bytes)?;
+
+        let mut tree = MerkleTree::new();
+        let (mut offset, mut records) = (0, 0);
+        while let Some((payload, next)) = read_record(&bytes, offset) {
+            match payload.split_first() {
+                Some((&OP_INSERT, key_hash)) => {
+                    if tree.update_count(HashBytes::new(key_hash.to_vec()), |count| count.checked_add(1)).is_err() {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "count overflow in log"));
+                    }
+                }
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown log operation")),
+            }
+            offset = next;
+            records += 1;
+        }
+        // Drop the torn or corrupted tail so new records follow the last valid one
+        if offset < bytes.len() {
+            log.set_len(offset as u64)?;
+            log.sync_all()?;
+        }
+        log.seek(SeekFrom::Start(offset as u64))?;
+        Ok(DurableTree { tree, log, records, end: offset as u64, poisoned: false })
+    }
+
+    /// Appends and syncs the insert before applying it. On failure the record is cut from
+    /// the log again; if even that fails, every later insert is refused.
+    pub fn insert<T: Hashable + Debug>(&mut self, key: T) -> io::Result<()> {
+        if self.poisoned {
+            return Err(io::Error::other("log poisoned by an earlier failed write"));
+        }
+        let hash = self.tree.hash_key(&key);
+        let count = self.tree.count_of_hash(&hash).checked_add(1);
+        let count = count.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "count overflow"))?;
+        let mut payload = vec![OP_INSERT];
+        payload.extend(hash.bytes());
+        let record = encode_record(&payload);
+        if let Err(error) = self.append(&record) {
+            // Part or all of the record may have reached the file
+            if self.rollback().is_err() {
+                self.poisoned = true;
+            }
+            return Err(error);
+        }
+        self.end += record.len() as u64;
+        self.records += 1;
+        self.tree.write_count(hash, count);
+        Ok(())
+    }
+
+    fn append(&mut self, record: &[u8]) -> io::Result<()> {
+        self.log.write_all(record)?;
+        self.log.sync_data()
+    }
+
+    fn rollback(&mut self) -> io::Result<()> {
+        self.log.set_len(self.end)?;
+        self.log.sync_all()?;
+        self.log.seek(SeekFrom::Start(self.end))?;
+        Ok(())
+    }
+
+    pub fn contains<T: Hashable + Debug>(&mut self, key: T) -> Option<u64> {
+        self.tree.contains(key)
+    }
+
+    pub fn get_hash(&self) -> Option<Vec<u8>> {
+        self.tree.get_hash()
+    }
+
+    /// Number of records in the log.
+    pub fn records(&self) -> usize {
+        self.records
+    }
+
+    pub fn tree(&self) -> &MerkleTree {
+        &self.tree
+    }
+}
+
+fn checksum(length: &[u8], payload: &[u8]) -> Vec<u8> {
+    let mut bytes = length.to_vec();
+    bytes.extend(payload);
+    bytes.raw_hash()[..CHECKSUM_LENGTH].to_vec()
+}
+
+fn encode_record(payload: &[u8]) -> Vec<u8> {
+    let length = (payload.len() as u32).to_le_bytes();
+    let mut record = length.to_vec();
+    record.extend(payload);
+    record.extend(checksum(&length, payload));
+    record
+}
+
+// Returns the payload starting at `offset` and the offset of the next record
+fn read_record(bytes: &[u8], offset: usize) -> Option<(&[u8], usize)> {
+    let length_bytes = bytes.get(offset..offset + 4)?;
+    let mut length = [0; 4];
+    length.copy_from_slice(length_bytes);
+    let payload_start = offset + 4;
+    let payload_end = payload_start.checked_add(u32::from_le_bytes(length) as usize)?;
+    let payload = bytes.get(payload_start..payload_end)?;
+    let stored = bytes.get(payload_end..payload_end + CHECKSUM_LENGTH)?;
+    if stored != checksum(length_bytes, payload).as_slice() {
+        return None;
+    }
+    Some((payload, payload_end + CHECKSUM_LENGTH))
+}